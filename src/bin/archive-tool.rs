@@ -10,25 +10,44 @@ use clap::Parser;
 use log::{debug, info, warn};
 use rayon::prelude::*;
 use std::cell::RefCell;
-use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
 use std::sync::mpsc::sync_channel;
 use std::thread::spawn;
-use std::{fs, io};
+use std::time::Duration;
 use tempfile::NamedTempFile;
-use wplace_tools::checksum::chunk_checksum;
-use wplace_tools::indexed_png::read_png;
+use wplace_tools::chain::{ChainChunkFetcher, ChainReplay, open_chain_base};
+use wplace_tools::checksum::{ChecksumAlgorithm, ChunkStates, chunk_checksum, state_digest};
+use wplace_tools::diff::{ChangeStats, RecentStates, Shard};
+use wplace_tools::indexed_png::read_png_reader;
+use wplace_tools::layout::ChunkPathLayout;
+use wplace_tools::tar::{
+    ChunksTarReader, ChunksTarWriter, is_tarball, read_states_file, write_states_file,
+};
+use wplace_tools::zip::ChunksZipWriter;
 use wplace_tools::{
-    chunk_buf, collect_chunks, diff, new_chunk_file, open_file_range,
-    set_up_logger, stylized_progress_bar, ChunkFetcher, DirChunkFetcher, ExitOnError, TarChunkFetcher,
-    CHUNK_LENGTH, DIFF_DATA_ZSTD_COMPRESSION_LEVEL, MUTATION_MASK, PALETTE_INDEX_MASK,
+    ChunkFetcher, ChunkNumber, ChunkProcessError, ChunkSelection, DIFF_DATA_ZSTD_COMPRESSION_LEVEL,
+    DiffFilesCollector, DirChunkFetcher, Iso8601Name, MUTATION_MASK, PALETTE_INDEX_MASK,
+    TarChunkFetcher, apply_chunk, chunk_buf, diff, extract_datetime, new_chunk_file,
+    open_chunk_fetcher, open_diff_source, parent_dir, set_up_logger, stylized_progress_bar,
+    validate_chunk_checksum, zstd_decompress,
 };
+use yeet_ops::yeet;
 
 mod cli {
-    use clap::{Args, Parser, Subcommand, ValueHint};
+    use clap::{Args, Parser, Subcommand, ValueEnum, ValueHint};
+    use std::collections::BTreeSet;
     use std::path::PathBuf;
-    use wplace_tools::TilesRange;
+    use wplace_tools::checksum::ChecksumAlgorithm;
+    use wplace_tools::diff::Shard;
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{
+        ChunkSelection, TilesRange, parse_chunk_string, read_chunk_list, read_chunk_mask,
+    };
 
     #[derive(Debug, Parser)]
     #[command(author, version)]
@@ -36,6 +55,9 @@ mod cli {
     pub struct Cli {
         #[command(subcommand)]
         pub command: Commands,
+
+        #[command(flatten, next_help_heading = "Archive layout")]
+        pub layout: ChunkPathLayout,
     }
 
     #[derive(Debug, Subcommand)]
@@ -50,43 +72,444 @@ mod cli {
 
             #[arg(value_name = "OUTPUT", value_hint = ValueHint::FilePath)]
             output: PathBuf,
+
+            /// Directory or SquashFS image of the diff chain that ends at `base`. If present,
+            /// chunks returning to a recent state are stored as references to it.
+            #[arg(long, value_hint = ValueHint::AnyPath)]
+            history: Vec<PathBuf>,
+
+            /// Number of the latest diffs in `history` to look up.
+            #[arg(long, default_value = "8")]
+            history_window: usize,
+
+            /// Base snapshot of the chain in `history`. Required by `verify` for resolving
+            /// references. Tarball/folder is supported.
+            #[arg(long, value_hint = ValueHint::AnyPath, requires = "history")]
+            history_base: Option<PathBuf>,
+
+            /// Algorithm for checksums of diff data, header and index.
+            #[arg(long, value_enum, default_value_t)]
+            checksum: ChecksumAlgorithm,
+
+            /// Re-apply the created diff to `base` and compare the result with `new` before
+            /// writing the output. References are resolved by replaying `history` from
+            /// `history-base`.
+            #[arg(long)]
+            verify: bool,
+
+            /// Only diff the chunks of shard `<index>/<count>`, split by chunk column. Combine
+            /// the outputs of all shards with `merge-shards`.
+            #[arg(long)]
+            shard: Option<Shard>,
+        },
+
+        /// Combine the shards created by `diff --shard` into one diff.
+        MergeShards {
+            /// Shard files; all of them are required
+            #[arg(value_hint = ValueHint::FilePath, num_args = 1.., required = true)]
+            shards: Vec<PathBuf>,
+
+            #[arg(short, long, value_hint = ValueHint::FilePath)]
+            output: PathBuf,
+        },
+
+        /// Cut a diff into shards by chunk column, e.g. for partial downloads.
+        ///
+        /// Shard `i` of diff `<name>.diff` is written to `<name>.shard-<i>-of-<count>.diff`.
+        SplitDiff {
+            #[arg(value_hint = ValueHint::FilePath)]
+            diff: PathBuf,
+
+            /// Number of shards
+            #[arg(short = 'n', long)]
+            count: u16,
+
+            /// Output directory
+            #[arg(short, long, value_hint = ValueHint::DirPath)]
+            output_dir: PathBuf,
+        },
+
+        /// Create one diff between two snapshots of a diff chain, without restoring them to disk.
+        DiffChain {
+            /// Initial snapshot of the chain. Tarball/folder is supported.
+            #[arg(short, long, value_hint = ValueHint::AnyPath)]
+            base_snapshot: PathBuf,
+
+            /// Directory or SquashFS image containing all the .diff files
+            #[arg(short, long, value_hint = ValueHint::AnyPath, required = true)]
+            diff_source: Vec<PathBuf>,
+
+            /// Snapshot name to diff from. If not present, use the base snapshot.
+            #[arg(long)]
+            from: Option<String>,
+
+            /// Snapshot name to diff to. If not present, use the newest one in `diff_source`.
+            #[arg(long)]
+            to: Option<String>,
+
+            #[arg(value_name = "OUTPUT", value_hint = ValueHint::FilePath)]
+            output: PathBuf,
+
+            /// Algorithm for checksums of diff data, header and index.
+            #[arg(long, value_enum, default_value_t)]
+            checksum: ChecksumAlgorithm,
+
+            /// Re-apply the created diff and compare the result before writing the output.
+            #[arg(long)]
+            verify: bool,
         },
 
         /// Apply diff files.
         Apply(ApplyCmd),
 
+        /// Thin out old history by a retention policy.
+        ///
+        /// Diffs in the same time bucket are combined into one diff named after the bucket's
+        /// last snapshot.
+        Thin {
+            /// Base snapshot of the chain. Tarball/folder is supported.
+            #[arg(short, long, value_hint = ValueHint::AnyPath)]
+            base_snapshot: PathBuf,
+
+            /// Directory containing the .diff files. It's modified in place.
+            #[arg(short, long, value_hint = ValueHint::DirPath)]
+            diff_dir: PathBuf,
+
+            /// Retention rules `<age>=<resolution>`, from young to old. Ages are relative to the
+            /// newest snapshot; `*` matches all the rest. Resolution `all` keeps every diff.
+            /// Units: s, m, h, d, w.
+            #[arg(long, default_value = "7d=all,30d=1h,*=1d")]
+            policy: String,
+
+            /// Only list the buckets to be combined.
+            #[arg(long)]
+            dry_run: bool,
+        },
+
+        /// Move the base of a diff chain forward to snapshot `at`.
+        ///
+        /// The snapshot is reconstructed and written as the new base tarball, and the diffs up
+        /// to it are moved aside or archived. Later diffs referencing them are rewritten, so the
+        /// remaining chain stays self-contained.
+        Rebase {
+            /// Current base snapshot of the chain. Tarball/folder is supported.
+            #[arg(short, long, value_hint = ValueHint::AnyPath)]
+            base_snapshot: PathBuf,
+
+            /// Directory containing the .diff files. It's modified in place.
+            #[arg(short, long, value_hint = ValueHint::DirPath)]
+            diff_dir: PathBuf,
+
+            /// Snapshot name of the new base
+            #[arg(short = 't', long)]
+            at: String,
+
+            /// Path of the new base tarball. Defaults to `<at>.tar` next to the current base.
+            #[arg(short, long, value_hint = ValueHint::FilePath)]
+            output: Option<PathBuf>,
+
+            /// Directory to move the old diffs to
+            #[arg(long, value_hint = ValueHint::DirPath, required_unless_present = "archive", conflicts_with = "archive")]
+            move_to: Option<PathBuf>,
+
+            /// Tarball to pack the old diffs into; they're deleted afterwards
+            #[arg(long, value_hint = ValueHint::FilePath)]
+            archive: Option<PathBuf>,
+        },
+
+        /// Append a new snapshot tarball to a diff chain.
+        ///
+        /// The diff from the previous snapshot of the chain is written to `<chain>/<name>.diff`.
+        /// If the previous full snapshot is not available, its state is reconstructed from the
+        /// chain.
+        Ingest {
+            #[arg(value_name = "NEW", value_hint = ValueHint::FilePath)]
+            new: PathBuf,
+
+            #[command(flatten)]
+            args: IngestArgs,
+        },
+
+        /// Watch a directory, and ingest snapshot tarballs into a diff chain as they land.
+        ///
+        /// Tarballs are ingested in name order once they stay unmodified for `settle`.
+        Watch {
+            #[arg(value_name = "INCOMING", value_hint = ValueHint::DirPath)]
+            incoming: PathBuf,
+
+            #[command(flatten)]
+            args: IngestArgs,
+
+            /// Polling interval. Units: s, m, h, d, w.
+            #[arg(long, default_value = "1m")]
+            interval: String,
+
+            /// Time a tarball has to stay unmodified to be considered completely written.
+            #[arg(long, default_value = "30s")]
+            settle: String,
+
+            /// Delete ingested tarballs. The newest one is kept as the parent of the next
+            /// snapshot.
+            #[arg(long)]
+            delete: bool,
+
+            /// Scan once and exit.
+            #[arg(long)]
+            once: bool,
+        },
+
+        /// Write a manifest of a diff source: name, size, SHA-256, entry counts and parent of
+        /// each diff. With `--check`, compare the source against the manifest instead.
+        Catalog {
+            /// Directory or SquashFS image containing the .diff files
+            #[arg(value_hint = ValueHint::AnyPath)]
+            diff_source: PathBuf,
+
+            /// Manifest path. Defaults to `manifest.json` in the directory, or
+            /// `<image>.manifest.json` next to a SquashFS image.
+            #[arg(short, long, value_hint = ValueHint::FilePath)]
+            manifest: Option<PathBuf>,
+
+            /// Report missing, extra and altered diffs, and gaps in the chain.
+            #[arg(long)]
+            check: bool,
+
+            /// With `--check`, also report consecutive snapshots further apart than this.
+            /// Units: s, m, h, d, w.
+            #[arg(long, requires = "check")]
+            max_gap: Option<String>,
+        },
+
+        /// Compare two diff chains built from the same snapshots, diff by diff.
+        ///
+        /// Chunk checksums in the indices are compared, so differently encoded chains still
+        /// match. Divergences are reported where they first appear.
+        CompareChains {
+            /// Directory or SquashFS image of the first chain
+            #[arg(long, value_hint = ValueHint::AnyPath, required = true)]
+            left: Vec<PathBuf>,
+
+            /// Directory or SquashFS image of the second chain
+            #[arg(long, value_hint = ValueHint::AnyPath, required = true)]
+            right: Vec<PathBuf>,
+        },
+
+        /// Write a diff containing only the selected chunks. Diff data is copied without
+        /// recompression.
+        FilterDiff {
+            #[arg(value_name = "DIFF", value_hint = ValueHint::FilePath)]
+            diff: PathBuf,
+
+            #[arg(value_name = "OUTPUT", value_hint = ValueHint::FilePath)]
+            output: PathBuf,
+
+            #[command(flatten)]
+            selection: ChunkSelectionArg,
+        },
+
+        /// Export a region of a diff chain: a regional base tarball and the filtered diffs,
+        /// which can be used by `retrieve` directly.
+        ExportRegion {
+            /// Base snapshot of the chain. Tarball/folder is supported.
+            #[arg(short, long, value_hint = ValueHint::AnyPath)]
+            base_snapshot: PathBuf,
+
+            /// Directory or SquashFS image containing all the .diff files
+            #[arg(short, long, value_hint = ValueHint::AnyPath, required = true)]
+            diff_source: Vec<PathBuf>,
+
+            #[command(flatten)]
+            selection: ChunkSelectionArg,
+
+            /// Output directory. The base tarball is written to it, and the diffs to `diffs/`.
+            #[arg(short, long, value_hint = ValueHint::DirPath)]
+            output: PathBuf,
+        },
+
         /// Compare two archives. This is used to verify if a diff-apply pipeline works correctly.
+        ///
+        /// Tarball/ZIP/folder is supported. Added, removed and differing chunks are reported;
+        /// exits with 1 if the archives differ.
         Compare {
-            #[arg(value_name = "BASE", value_hint = ValueHint::FilePath)]
+            #[arg(value_name = "BASE", value_hint = ValueHint::AnyPath)]
             base: PathBuf,
 
-            #[arg(value_name = "NEW", value_hint = ValueHint::FilePath)]
+            #[arg(value_name = "NEW", value_hint = ValueHint::AnyPath)]
             new: PathBuf,
+
+            /// Print the report as JSON
+            #[arg(long)]
+            json: bool,
+
+            /// Directory to write an image of the differences of each differing chunk to
+            #[arg(long, value_hint = ValueHint::DirPath)]
+            visual_diff: Option<PathBuf>,
         },
 
-        /// Filter chunk files by `tiles_range`
+        /// Copy the selected chunks of a snapshot
+        ///
+        /// Tarball/ZIP/folder input is supported. The output is a tarball if its name ends
+        /// with `.tar`, otherwise a folder.
         Filter {
-            #[arg(value_name = "BASE", value_hint = ValueHint::FilePath)]
+            #[arg(value_name = "BASE", value_hint = ValueHint::AnyPath)]
             base: PathBuf,
 
-            #[arg(value_name = "OUTPUT", value_hint = ValueHint::FilePath)]
+            #[arg(value_name = "OUTPUT", value_hint = ValueHint::AnyPath)]
             output: PathBuf,
 
             #[command(flatten)]
-            tiles_range_arg: TilesRangeArg,
+            selection: ChunkSelectionArg,
+        },
+
+        /// Convert a snapshot between folder, tarball and ZIP.
+        ///
+        /// The output format is chosen by the extension of `OUTPUT`: `.tar`, `.zip`, otherwise a
+        /// folder. Archives are written in the wplace-archives layout with the file stem as the
+        /// root directory, and are byte-identical for identical chunks.
+        Convert {
+            #[arg(value_name = "INPUT", value_hint = ValueHint::AnyPath)]
+            input: PathBuf,
+
+            #[arg(value_name = "OUTPUT", value_hint = ValueHint::AnyPath)]
+            output: PathBuf,
+        },
+
+        /// Build the index files of snapshot tarballs.
+        ///
+        /// Tarballs are indexed on first use anyway; the index `<tarball>.idx` maps chunks to
+        /// their positions, so later runs needn't scan the whole tarball again. It's rebuilt
+        /// when the tarball's size or modification time changes.
+        IndexTar {
+            #[arg(value_name = "TARBALL", value_hint = ValueHint::FilePath, num_args = 1.., required = true)]
+            tars: Vec<PathBuf>,
         },
 
         /// Print info of a diff file
         Show {
             #[arg(value_hint = ValueHint::FilePath)]
             diff: PathBuf,
+
+            /// Print as JSON
+            #[arg(long)]
+            json: bool,
         },
 
-        /// Test a diff file.
-        Test {
+        /// List index entries of a diff file
+        Ls {
             #[arg(value_hint = ValueHint::FilePath)]
             diff: PathBuf,
+
+            #[command(flatten)]
+            tiles_range_arg: TilesRangeArg,
+
+            /// Only list changed chunks
+            #[arg(long)]
+            changed: bool,
+
+            /// Also print the number of mutated pixels. Diff data of version 4 files
+            /// is decompressed to count them.
+            #[arg(long)]
+            changes: bool,
+
+            #[arg(long, value_enum, default_value_t)]
+            sort: LsSort,
+
+            /// Reverse the order
+            #[arg(long)]
+            reverse: bool,
+
+            /// Print as JSON
+            #[arg(long)]
+            json: bool,
+        },
+
+        /// Test diff files. Checksums of diff data, header and index are verified (version 5+),
+        /// as well as the file structure and the decompressed diff data.
+        Test {
+            /// A diff file, or directories/SquashFS images containing diff files
+            #[arg(value_hint = ValueHint::AnyPath, num_args = 1.., required = true)]
+            diff: Vec<PathBuf>,
         },
+
+        /// Replay a whole diff chain from its base snapshot with checksum validation.
+        /// No images are written.
+        VerifyChain {
+            /// Initial snapshot of the chain. Tarball/folder is supported.
+            #[arg(short, long, value_hint = ValueHint::AnyPath)]
+            base_snapshot: PathBuf,
+
+            /// Directory or SquashFS image containing all the .diff files
+            #[arg(short, long, value_hint = ValueHint::AnyPath, required = true)]
+            diff_source: Vec<PathBuf>,
+
+            /// Don't validate the unchanged chunks of the base snapshot.
+            #[arg(long)]
+            skip_base: bool,
+        },
+    }
+
+    #[derive(Args, Debug)]
+    #[group(required = true, multiple = true)]
+    pub struct ChunkSelectionArg {
+        /// Range of tiles. Format: <x-min>,<x-max>,<y-min>,<y-max>. Can be given multiple times
+        #[arg(short = 'r', long)]
+        pub tiles_range: Vec<String>,
+
+        /// Chunks. Format: x1-y1,x2-y2,x3-y3,... or x1-y1..x2-y2
+        #[arg(short, long)]
+        pub chunks: Option<String>,
+
+        /// File listing chunks, one `--chunks` string per line
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        pub chunk_list: Option<PathBuf>,
+
+        /// PNG image whose pixel (x, y) selects chunk (x, y), if not transparent (or black)
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        pub mask: Option<PathBuf>,
+    }
+
+    impl ChunkSelectionArg {
+        pub fn parse(&self) -> anyhow::Result<ChunkSelection> {
+            let tiles_ranges = self
+                .tiles_range
+                .iter()
+                .map(|s| {
+                    TilesRange::parse_str(s)
+                        .ok_or_else(|| anyhow::anyhow!("Malformed tiles range: {s}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let mut chunks = None::<BTreeSet<_>>;
+            if let Some(s) = &self.chunks {
+                chunks
+                    .get_or_insert_default()
+                    .extend(parse_chunk_string(s)?);
+            }
+            if let Some(path) = &self.chunk_list {
+                chunks
+                    .get_or_insert_default()
+                    .extend(read_chunk_list(path)?);
+            }
+            if let Some(path) = &self.mask {
+                chunks
+                    .get_or_insert_default()
+                    .extend(read_chunk_mask(path)?);
+            }
+            Ok(ChunkSelection {
+                tiles_ranges,
+                chunks,
+            })
+        }
+    }
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum LsSort {
+        /// Chunk number
+        #[default]
+        Chunk,
+        /// Size of the compressed diff data
+        Size,
+        /// Number of mutated pixels
+        Changes,
     }
 
     #[derive(Args, Debug)]
@@ -104,6 +527,40 @@ mod cli {
         }
     }
 
+    #[derive(Args, Debug, Clone)]
+    pub struct IngestArgs {
+        /// Directory containing the .diff files of the chain
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        pub chain: PathBuf,
+
+        /// Base snapshot of the chain. Required when the chain has no diffs yet, or the previous
+        /// full snapshot is not found.
+        #[arg(short, long, value_hint = ValueHint::AnyPath)]
+        pub base_snapshot: Option<PathBuf>,
+
+        /// Directory to look for the previous full snapshot. Defaults to the directory of the
+        /// new snapshot.
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        pub snapshots: Option<PathBuf>,
+
+        /// Store chunks returning to a recent state as references to the chain.
+        #[arg(long)]
+        pub references: bool,
+
+        /// Number of the latest diffs to look up for `--references`.
+        #[arg(long, default_value = "8")]
+        pub history_window: usize,
+
+        /// Algorithm for checksums of diff data, header and index.
+        #[arg(long, value_enum, default_value_t)]
+        pub checksum: ChecksumAlgorithm,
+
+        /// Re-apply the created diff and compare the result before writing the output. With
+        /// `--references`, the chain is replayed from `--base-snapshot` to resolve them.
+        #[arg(long)]
+        pub verify: bool,
+    }
+
     #[derive(Args, Debug)]
     pub struct ApplyCmd {
         /// Initial archive. Tarball/folder is supported.
@@ -114,7 +571,8 @@ mod cli {
         #[arg(value_hint = clap::ValueHint::FilePath, num_args = 1.., required = true)]
         pub diffs: Vec<PathBuf>,
 
-        /// The final produced snapshot path after all diffs being applied.
+        /// The final produced snapshot path after all diffs being applied. It's written as a
+        /// tarball or ZIP if it ends with `.tar` or `.zip`, otherwise as a folder.
         #[arg(value_hint = clap::ValueHint::FilePath, short, long)]
         pub output: Option<PathBuf>,
 
@@ -128,15 +586,6 @@ mod cli {
     }
 }
 
-#[inline(always)]
-fn compare_png(base: impl AsRef<Path>, new: impl AsRef<Path>) -> anyhow::Result<bool> {
-    let mut img1 = vec![0_u8; CHUNK_LENGTH];
-    let mut img2 = vec![0_u8; CHUNK_LENGTH];
-    read_png(base, &mut img1)?;
-    read_png(new, &mut img2)?;
-    Ok(img1 == img2)
-}
-
 /// Diff the two buffer. New data will be written back to `base_buf`.
 #[inline(always)]
 fn diff_chunk(base_buf: &mut [u8], new_buf: &[u8]) {
@@ -150,15 +599,8 @@ fn diff_chunk(base_buf: &mut [u8], new_buf: &[u8]) {
     }
 }
 
-/// Returns compressed diff between two images.
-#[inline(always)]
-fn diff_chunk_compressed(base_buf: &mut [u8], new_buf: &[u8]) -> anyhow::Result<Vec<u8>> {
-    diff_chunk(base_buf, new_buf);
-    Ok(zstd::encode_all(
-        &mut (&base_buf[..]),
-        DIFF_DATA_ZSTD_COMPRESSION_LEVEL,
-    )?)
-}
+/// Number of chunks diffed in parallel before their results are written in order
+const DIFF_BATCH_SIZE: usize = 4096;
 
 thread_local! {
     static COMPRESSOR_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
@@ -167,454 +609,2951 @@ thread_local! {
 fn main() -> anyhow::Result<()> {
     set_up_logger();
     let args = cli::Cli::parse();
+    let layout = &args.layout;
     match args.command {
-        Commands::Diff { base, new, output } => {
+        Commands::Diff {
+            base,
+            new,
+            output,
+            history,
+            history_window,
+            history_base,
+            checksum,
+            verify,
+            shard,
+        } => {
+            let history = match history.is_empty() {
+                true => None,
+                false => Some(open_history(&base, &history)?),
+            };
+            let chain_history = match (&history, verify) {
+                (Some(source), true) => {
+                    let Some(history_base) = &history_base else {
+                        yeet!(anyhow::anyhow!(
+                            "Verifying a diff with `--history` requires `--history-base`"
+                        ));
+                    };
+                    let names = source.name_iter().cloned().collect::<Vec<_>>();
+                    Some(ChainHistory {
+                        base: open_chain_base(history_base, &**source, &names, layout)?,
+                        source: Arc::clone(source),
+                    })
+                }
+                _ => None,
+            };
+            let options = DiffOptions {
+                recent_states: match &history {
+                    Some(source) => {
+                        info!("Collecting recent chunk states from history...");
+                        Some(RecentStates::collect(&**source, history_window)?)
+                    }
+                    None => None,
+                },
+                base_states: match &history {
+                    Some(source) => Some(collect_base_states(&**source, &source.last())?),
+                    None => None,
+                },
+                checksum_algorithm: checksum,
+                verify,
+                history: chain_history,
+                shard: shard.map(|x| x.of_diff(shard_identity(&base, &new))),
+                region: Vec::new(),
+            };
+
             // a special handle for directly processing tar files
-            if base.extension() == Some(OsStr::new("tar"))
-                && new.extension() == Some(OsStr::new("tar"))
-            {
-                do_diff_for_tar(base, new, output)?;
+            if is_tarball(&base) && is_tarball(&new) {
+                do_diff_for_tar(base, new, output, options, layout)?;
                 return Ok(());
             }
 
-            do_diff_for_directory(base, new, output)?;
+            do_diff_for_directory(base, new, output, options)?;
+        }
+
+        Commands::DiffChain {
+            base_snapshot,
+            diff_source,
+            from,
+            to,
+            output,
+            checksum,
+            verify,
+        } => {
+            let options = DiffOptions {
+                recent_states: None,
+                base_states: None,
+                checksum_algorithm: checksum,
+                verify,
+                history: None,
+                shard: None,
+                region: Vec::new(),
+            };
+            do_diff_for_chain(
+                &base_snapshot,
+                &diff_source,
+                from.as_deref(),
+                to.as_deref(),
+                output,
+                options,
+                layout,
+            )?;
+        }
+
+        Commands::MergeShards { shards, output } => {
+            shard::merge(&shards, &output)?;
+        }
+
+        Commands::SplitDiff {
+            diff,
+            count,
+            output_dir,
+        } => {
+            shard::split(&diff, count, &output_dir)?;
         }
 
         Commands::Apply(cmd) => {
-            apply::main(cmd)?;
+            apply::main(cmd, layout)?;
         }
 
-        Commands::Compare { base, new } => {
-            info!("Collecting files 'base'...");
-            let mut base_collected = collect_chunks(&base, None)?;
-            info!("Collecting files 'new'...");
-            let mut new_collected = collect_chunks(&new, None)?;
+        Commands::Thin {
+            base_snapshot,
+            diff_dir,
+            policy,
+            dry_run,
+        } => {
+            let policy = thin::Policy::parse(&policy)?;
+            thin::main(&base_snapshot, &diff_dir, &policy, dry_run, layout)?;
+        }
 
-            base_collected.sort();
-            new_collected.sort();
-            if base_collected != new_collected {
-                return Err(anyhow::anyhow!("File lists differ."));
-            }
+        Commands::Rebase {
+            base_snapshot,
+            diff_dir,
+            at,
+            output,
+            move_to,
+            archive,
+        } => {
+            let old_diffs = match (move_to, archive) {
+                (Some(dir), _) => rebase::OldDiffs::MoveTo(dir),
+                (None, Some(tar)) => rebase::OldDiffs::Archive(tar),
+                (None, None) => unreachable!("Clap ensures"),
+            };
+            rebase::main(&base_snapshot, &diff_dir, &at, output, old_diffs, layout)?;
+        }
+
+        Commands::Ingest { new, args } => {
+            ingest::ingest(&new, &args, layout)?;
+        }
 
-            let length = base_collected.len();
-            info!("Processing {} files...", length);
-            let progress = stylized_progress_bar(length as u64);
+        Commands::Watch {
+            incoming,
+            args,
+            interval,
+            settle,
+            delete,
+            once,
+        } => {
+            let options = watch::Options {
+                interval: Duration::from_millis(thin::parse_duration(&interval)? as u64),
+                settle: Duration::from_millis(thin::parse_duration(&settle)? as u64),
+                delete,
+                once,
+            };
+            watch::main(&incoming, &args, &options, layout)?;
+        }
 
-            // job-stealing parallelization is enough here
-            base_collected.into_iter().par_bridge().for_each(|(x, y)| {
-                let base_file = base.join(format!("{x}/{y}.png"));
-                let new_file = new.join(format!("{x}/{y}.png"));
-                let result = compare_png(&base_file, &new_file).unwrap();
-                if !result {
-                    info!("{} and {} differ", base_file.display(), new_file.display());
+        Commands::Catalog {
+            diff_source,
+            manifest,
+            check,
+            max_gap,
+        } => {
+            let manifest = manifest.unwrap_or_else(|| catalog::default_manifest_path(&diff_source));
+            if check {
+                if !catalog::check(&diff_source, &manifest, max_gap.as_deref())? {
+                    exit(1);
                 }
-                progress.inc(1);
-            });
-            progress.finish();
+            } else {
+                catalog::write(&diff_source, &manifest)?;
+            }
+        }
+
+        Commands::CompareChains { left, right } => {
+            if !compare_chains::main(&left, &right)? {
+                exit(1);
+            }
+        }
+
+        Commands::FilterDiff {
+            diff,
+            output,
+            selection,
+        } => {
+            let diff_file = diff::DiffFile::open(File::open_buffered(&diff)?)?;
+            let (kept, changed, total) = filter_diff(diff_file, &output, &selection.parse()?)?;
+            info!("Kept {kept} of {total} entries ({changed} changed)");
+        }
+
+        Commands::ExportRegion {
+            base_snapshot,
+            diff_source,
+            selection,
+            output,
+        } => {
+            export_region::main(
+                &base_snapshot,
+                &diff_source,
+                &selection.parse()?,
+                &output,
+                layout,
+            )?;
+        }
+
+        Commands::Compare {
+            base,
+            new,
+            json,
+            visual_diff,
+        } => {
+            if !compare::main(&base, &new, json, visual_diff.as_deref(), layout)? {
+                exit(1);
+            }
         }
 
         Commands::Filter {
             base,
             output,
-            tiles_range_arg,
+            selection,
         } => {
-            fs::create_dir_all(&output)?;
-            info!("Collecting files...");
-            let collected = collect_chunks(&base, tiles_range_arg.parse())?;
-            info!("Processing {} files...", collected.len());
-            let progress = stylized_progress_bar(collected.len() as u64);
-
-            collected.into_par_iter().for_each(|(x, y)| {
-                let base_file = base.join(format!("{x}/{y}.png"));
-                let output_file = new_chunk_file(&output, (x, y), "png");
-                fs::copy(base_file, output_file).unwrap();
-                progress.inc(1);
-            });
-            progress.finish();
+            filter::main(&base, &output, &selection.parse()?, layout)?;
         }
 
-        Commands::Show { diff } => {
-            let mut diff_file = diff::DiffFile::open(File::open_buffered(&diff)?)?;
-            println!("Version: {}", diff::VERSION);
-            println!(
-                "Metadata: {}",
-                serde_json::to_string(&diff_file.metadata).unwrap()
-            );
-            let index = diff_file.collect_index()?;
-            println!("Total chunks: {}", index.len());
-            println!(
-                "Changed chunks: {}",
-                index.iter().filter(|x| x.1.is_changed()).count()
-            );
+        Commands::Convert { input, output } => {
+            if output.exists() {
+                yeet!(anyhow::anyhow!("Output exists: {}", output.display()));
+            }
+            info!("Reading {}...", input.display());
+            let fetcher = open_chunk_fetcher(&input, true, layout)?;
+            info!("Writing to {}...", output.display());
+            write_snapshot(&*fetcher, &output, &ChunkSelection::default())?;
+        }
+
+        Commands::IndexTar { tars } => {
+            for path in &tars {
+                info!("Indexing {}...", path.display());
+                let count = ChunksTarReader::build_index_file(path, layout)?;
+                info!(
+                    "Indexed {count} chunks to {}",
+                    wplace_tools::tar::index_path(path).display()
+                );
+            }
+        }
+
+        Commands::Show { diff, json } => {
+            inspect::show(&diff, json)?;
+        }
+
+        Commands::Ls {
+            diff,
+            tiles_range_arg,
+            changed,
+            changes,
+            sort,
+            reverse,
+            json,
+        } => {
+            let options = inspect::LsOptions {
+                tiles_range: tiles_range_arg.parse(),
+                changed_only: changed,
+                changes,
+                sort,
+                reverse,
+                json,
+            };
+            inspect::ls(&diff, &options)?;
         }
 
         Commands::Test { diff } => {
-            let mut reader = diff::DiffFile::open(File::open_buffered(&diff)?)?;
-            let index = reader.collect_index()?;
-            assert_eq!(reader.entry_count as usize, index.len());
-            let pb = stylized_progress_bar(index.len() as u64);
-            index.into_par_iter().for_each(|(_n, e)| {
-                let result: anyhow::Result<()> = try {
-                    match e.is_changed() {
-                        false => {}
-                        true => {
-                            let portion = open_file_range(&diff, e.pos, e.len)?;
+            if !validate::main(&diff)? {
+                exit(1);
+            }
+        }
 
-                            let mut decoder = zstd::Decoder::new(portion)?;
-                            io::copy(&mut decoder, &mut io::sink())?;
-                        }
-                    }
-                    pb.inc(1);
-                };
-                result.exit_on_error();
-            });
-            pb.finish();
-            println!("Done.");
+        Commands::VerifyChain {
+            base_snapshot,
+            diff_source,
+            skip_base,
+        } => {
+            if !verify_chain::main(&base_snapshot, &diff_source, skip_base, layout)? {
+                exit(1);
+            }
         }
     }
 
     Ok(())
 }
 
+#[derive(Default)]
+struct DiffOptions {
+    /// Emit reference entries for chunks found in these states
+    recent_states: Option<RecentStates>,
+    /// Checksums and state digests of the base chunks, from the diff leading to the base or the
+    /// state file of the base tarball. Chunks with byte-identical PNG files take them instead of
+    /// being decoded.
+    base_states: Option<ChunkStates>,
+    checksum_algorithm: ChecksumAlgorithm,
+    /// Check the diff file by re-applying it before persisting
+    verify: bool,
+    /// Chain leading to the base snapshot, for verifying reference entries
+    history: Option<ChainHistory>,
+    /// Only diff the chunks of this shard
+    shard: Option<Shard>,
+    /// Region of the snapshots if they're regional; see `Metadata::region`
+    region: Vec<ChunkSelection>,
+}
+
+/// A diff chain: its base snapshot and diffs
+struct ChainHistory {
+    base: Arc<dyn ChunkFetcher + Send + Sync>,
+    source: Arc<dyn DiffFilesCollector + Send + Sync>,
+}
+
+enum ChunkDiff {
+    Unchanged,
+    Data(Vec<u8>, ChangeStats),
+    Reference(u16, ChangeStats),
+}
+
+/// Open the diff chain `history` that ends at the snapshot `base`.
+fn open_history(
+    base: &Path,
+    history: &[PathBuf],
+) -> anyhow::Result<Arc<dyn DiffFilesCollector + Send + Sync>> {
+    let source = open_diff_source(history)?;
+    // References are only valid if `history` is exactly the chain leading to `base`.
+    let last = source.last();
+    match base.file_name().and_then(extract_datetime) {
+        Some(name) if name != last => {
+            yeet!(anyhow::anyhow!(
+                "History doesn't end at the base snapshot: {last} vs {name}"
+            ));
+        }
+        Some(_) => {}
+        None => {
+            warn!("Can't get the snapshot name of 'base'; assuming history ends at it");
+        }
+    }
+    Ok(source)
+}
+
+/// Checksums and state digests of the chunks after diff `name` of `source`, i.e. of the
+/// snapshot it leads to. Entries without a state digest are left out.
+fn collect_base_states(source: &dyn DiffFilesCollector, name: &str) -> anyhow::Result<ChunkStates> {
+    let mut diff_file = diff::DiffFile::open(source.reader(name)?)?;
+    Ok(diff_file
+        .collect_index()?
+        .into_iter()
+        .filter_map(|(n, e)| Some((n, (e.checksum, e.state_digest.filter(|&x| x != 0)?))))
+        .collect())
+}
+
+/// Identity of the diff from `base` to `new`, recorded in all of its shards: the snapshot
+/// names, or the file names if they have none.
+fn shard_identity(base: &Path, new: &Path) -> String {
+    let name = |path: &Path| {
+        let file_name = path.file_name().unwrap_or(path.as_os_str());
+        extract_datetime(file_name).unwrap_or_else(|| file_name.to_string_lossy().into())
+    };
+    format!("{}..{}", name(base), name(new))
+}
+
+/// Diff `base_fetcher` to `new_fetcher` and write the diff file to `output`.
+///
+/// Returns the checksums and state digests of the diffed new chunks.
 fn do_diff(
     base_fetcher: impl ChunkFetcher + Send + Sync + 'static,
     new_fetcher: impl ChunkFetcher + Send + Sync + 'static,
     output: PathBuf,
-) -> anyhow::Result<()> {
+    options: DiffOptions,
+) -> anyhow::Result<ChunkStates> {
     info!("Creating diff file...");
-    let mut output_dir = output
-        .parent()
-        .expect("Can not get parent of the output file");
-    if output_dir == Path::new("") {
-        output_dir = Path::new(".");
-    }
-    let temp_file = NamedTempFile::new_in(output_dir)?;
+    let DiffOptions {
+        recent_states,
+        base_states,
+        checksum_algorithm,
+        verify,
+        history,
+        shard,
+        region,
+    } = options;
+    let base_fetcher = Arc::new(base_fetcher);
+    let new_fetcher = Arc::new(new_fetcher);
+    let temp_file = NamedTempFile::new_in(parent_dir(&output))?;
     debug!("temp_file: {}", temp_file.as_ref().display());
     let output_file = File::create_buffered(temp_file.as_ref())?;
-    let mut diff_file =
-        diff::DiffFileWriter::create(output_file, diff::Metadata::default(), diff::VERSION)?;
+    let metadata = diff::Metadata {
+        references: recent_states
+            .as_ref()
+            .map(|x| x.names.clone())
+            .unwrap_or_default(),
+        checksum_algorithm,
+        shard: shard.clone(),
+        region,
+    };
+    let mut diff_file = diff::DiffFileWriter::create(output_file, metadata, diff::VERSION)?;
 
     let (tx, rx) = sync_channel(1024);
-    info!("Processing {} files...", new_fetcher.chunks_len());
-
-    let progress = stylized_progress_bar(new_fetcher.chunks_len() as u64);
-    spawn(move || {
-        let chunks_iter = new_fetcher.chunks_iter();
-        chunks_iter.par_bridge().for_each_with(
-            (tx, chunk_buf!(), chunk_buf!()),
-            |(tx, base_buf, new_buf), (x, y)| {
-                let result: anyhow::Result<()> = try {
-                    let present = new_fetcher.fetch((x, y), new_buf)?;
-                    assert!(present);
-                    let base_chunk_present = base_fetcher.fetch((x, y), base_buf)?;
-                    if !base_chunk_present {
-                        base_buf.fill(0);
-                    }
-
-                    let checksum = chunk_checksum(new_buf);
-
-                    // It's expecting that a large percent of the chunks are not mutated.
-                    // Thus in this case, only computing diff for changed chunks can reduce the process time.
-                    let compressed_diff = if !base_chunk_present || base_buf != new_buf {
-                        let compressed_diff = diff_chunk_compressed(base_buf, new_buf).unwrap();
-                        Some(compressed_diff)
-                    } else {
-                        None
-                    };
-                    tx.send((x, y, compressed_diff, checksum)).unwrap();
-                    progress.inc(1);
-                };
-                result.exit_on_error();
-            },
+    let mut chunks = new_fetcher.chunks_iter().collect::<Vec<_>>();
+    if let Some(shard) = shard {
+        chunks.retain(|&n| shard.contains(n));
+        info!(
+            "Shard {shard}: {} of {} chunks",
+            chunks.len(),
+            new_fetcher.chunks_len()
         );
+    }
+    info!("Processing {} files...", chunks.len());
+    chunks.sort_unstable();
+    let progress = stylized_progress_bar(chunks.len() as u64);
+    let compare_png = base_fetcher.stores_png() && new_fetcher.stores_png();
+    let (base, new) = (Arc::clone(&base_fetcher), Arc::clone(&new_fetcher));
+    spawn(move || {
+        let (base_fetcher, new_fetcher) = (&*base, &*new);
+        // Chunks are diffed in parallel batch by batch, and results of each batch are sent
+        // in (x, y) order. This keeps the output reproducible.
+        for batch in chunks.chunks(DIFF_BATCH_SIZE) {
+            let results = batch
+                .par_iter()
+                .map_init(
+                    || (chunk_buf!(), chunk_buf!()),
+                    |(base_buf, new_buf), &(x, y)| {
+                        let result: anyhow::Result<_> = try {
+                            // checksum and state digest of the new chunk, if known without it
+                            let mut known = None;
+                            let (base_chunk_present, unchanged) = if compare_png {
+                                // Byte-identical PNG files need no decoding on the base side.
+                                // The new one is only decoded for the checksum if the base
+                                // state isn't known from the history or the base's state file.
+                                let new_png = new_fetcher.fetch_raw((x, y))?;
+                                assert!(!new_png.is_empty());
+                                let base_png = base_fetcher.fetch_raw((x, y))?;
+                                known = base_states
+                                    .as_ref()
+                                    .and_then(|s| s.get(&(x, y)))
+                                    .filter(|_| base_png == new_png)
+                                    .copied();
+                                if known.is_none() {
+                                    read_png_reader(Cursor::new(&new_png), new_buf)?;
+                                }
+                                if base_png == new_png {
+                                    (true, true)
+                                } else if base_png.is_empty() {
+                                    base_buf.fill(0);
+                                    (false, false)
+                                } else {
+                                    read_png_reader(Cursor::new(&base_png), base_buf)?;
+                                    (true, base_buf == new_buf)
+                                }
+                            } else {
+                                let present = new_fetcher.fetch((x, y), new_buf)?;
+                                assert!(present);
+                                let base_chunk_present = base_fetcher.fetch((x, y), base_buf)?;
+                                if !base_chunk_present {
+                                    base_buf.fill(0);
+                                }
+                                (base_chunk_present, base_buf == new_buf)
+                            };
+
+                            let (checksum, digest) = known.unwrap_or_else(|| {
+                                (chunk_checksum(new_buf), state_digest(new_buf))
+                            });
+
+                            // It's expecting that a large percent of the chunks are not mutated.
+                            // Thus in this case, only computing diff for changed chunks can reduce the process time.
+                            let chunk_diff = if !base_chunk_present || !unchanged {
+                                diff_chunk(base_buf, new_buf);
+                                let stats = ChangeStats::from_diff_data(base_buf);
+                                match recent_states
+                                    .as_ref()
+                                    .and_then(|s| s.lookup((x, y), checksum, digest))
+                                {
+                                    Some(r) => ChunkDiff::Reference(r, stats),
+                                    None => {
+                                        let compressed = zstd::encode_all(
+                                            &base_buf[..],
+                                            DIFF_DATA_ZSTD_COMPRESSION_LEVEL,
+                                        )?;
+                                        ChunkDiff::Data(compressed, stats)
+                                    }
+                                }
+                            } else {
+                                ChunkDiff::Unchanged
+                            };
+                            progress.inc(1);
+                            (x, y, chunk_diff, checksum, digest)
+                        };
+                        result
+                            .map_err(|e| anyhow::anyhow!("Failed to diff chunk {:?}: {e}", (x, y)))
+                    },
+                )
+                .collect::<Vec<_>>();
+            for r in results {
+                let failed = r.is_err();
+                // the receiver is gone if writing the diff failed
+                if tx.send(r).is_err() || failed {
+                    return;
+                }
+            }
+        }
         progress.finish();
     });
 
-    for (x, y, diff, checksum) in rx {
-        diff_file.add_entry((x, y), diff.as_deref(), checksum)?;
+    let mut new_states = ChunkStates::new();
+    for result in rx {
+        let (x, y, chunk_diff, checksum, digest) = result?;
+        new_states.insert((x, y), (checksum, digest));
+        match chunk_diff {
+            ChunkDiff::Unchanged => {
+                diff_file.add_entry((x, y), None, checksum, digest, ChangeStats::default())?
+            }
+            ChunkDiff::Data(data, stats) => {
+                diff_file.add_entry((x, y), Some(&data), checksum, digest, stats)?
+            }
+            ChunkDiff::Reference(r, stats) => {
+                diff_file.add_reference((x, y), r, checksum, digest, stats)?
+            }
+        }
     }
     diff_file.finalize()?;
+    if verify {
+        info!("Verifying diff file...");
+        verify_diff(
+            temp_file.as_ref(),
+            &*base_fetcher,
+            &*new_fetcher,
+            history.as_ref(),
+        )
+        .map_err(|e| anyhow::anyhow!("Verification failed; output is not written.\n{e}"))?;
+    }
     temp_file.persist(output)?;
-    Ok(())
+    Ok(new_states)
 }
 
-fn do_diff_for_directory(base: PathBuf, new: PathBuf, output: PathBuf) -> anyhow::Result<()> {
-    info!("Collecting files...");
-    let new_fetcher = DirChunkFetcher::new(&new, true)?;
-    let base_fetcher = DirChunkFetcher::new(&base, false)?;
-
-    do_diff(base_fetcher, new_fetcher, output)?;
+/// Fetch the PNG files of chunks of `fetcher` selected by `selection`, and pass them to `add`
+/// in ascending order. Absent chunks are skipped.
+fn for_each_snapshot_png(
+    fetcher: &(dyn ChunkFetcher + Sync),
+    selection: &ChunkSelection,
+    mut add: impl FnMut(ChunkNumber, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut chunks = fetcher
+        .chunks_iter()
+        .filter(|&n| selection.contains(n))
+        .collect::<Vec<_>>();
+    chunks.sort_unstable();
+
+    let progress = stylized_progress_bar(chunks.len() as u64);
+    for batch in chunks.chunks(DIFF_BATCH_SIZE) {
+        let pngs = batch
+            .par_iter()
+            .map(|&n| fetcher.fetch_raw(n))
+            .collect::<Vec<_>>();
+        for (&n, png) in batch.iter().zip(pngs) {
+            let png = png?;
+            if !png.is_empty() {
+                add(n, &png)?;
+            }
+            progress.inc(1);
+        }
+    }
+    progress.finish();
     Ok(())
 }
 
-fn do_diff_for_tar(base: PathBuf, new: PathBuf, output: PathBuf) -> anyhow::Result<()> {
-    info!("Indexing 'base' tarball...");
-    let base_tar = TarChunkFetcher::new(&base)?;
+/// Write chunks of `fetcher` selected by `selection` as a snapshot tarball with root directory
+/// `root_name`.
+fn write_snapshot_tar(
+    fetcher: &(dyn ChunkFetcher + Sync),
+    root_name: &str,
+    output: &Path,
+    selection: &ChunkSelection,
+) -> anyhow::Result<()> {
+    let temp_file = NamedTempFile::new_in(parent_dir(output))?;
+    let mut writer = ChunksTarWriter::new(File::create_buffered(temp_file.as_ref())?, root_name)?;
+    for_each_snapshot_png(fetcher, selection, |n, png| Ok(writer.add_chunk(n, png)?))?;
+    writer.finish()?.flush()?;
+    temp_file.persist(output)?;
+    Ok(())
+}
+
+/// Write chunks of `fetcher` selected by `selection` as a snapshot ZIP with root directory
+/// `root_name`.
+fn write_snapshot_zip(
+    fetcher: &(dyn ChunkFetcher + Sync),
+    root_name: &str,
+    output: &Path,
+    selection: &ChunkSelection,
+) -> anyhow::Result<()> {
+    let temp_file = NamedTempFile::new_in(parent_dir(output))?;
+    let mut writer = ChunksZipWriter::new(File::create_buffered(temp_file.as_ref())?, root_name)?;
+    for_each_snapshot_png(fetcher, selection, |n, png| writer.add_chunk(n, png))?;
+    writer.finish()?.flush()?;
+    temp_file.persist(output)?;
+    Ok(())
+}
+
+/// Write chunks of `fetcher` selected by `selection` to `output`, as a tarball or ZIP by its
+/// extension, otherwise as a folder. Archives get the file stem as their root directory.
+fn write_snapshot(
+    fetcher: &(dyn ChunkFetcher + Sync),
+    output: &Path,
+    selection: &ChunkSelection,
+) -> anyhow::Result<()> {
+    let extension = output.extension().map(|x| x.to_ascii_lowercase());
+    if extension == Some("tar".into()) || extension == Some("zip".into()) {
+        let root_name = output
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid output name"))?;
+        return match extension == Some("tar".into()) {
+            true => write_snapshot_tar(fetcher, root_name, output, selection),
+            false => write_snapshot_zip(fetcher, root_name, output, selection),
+        };
+    }
+
+    let chunks = fetcher
+        .chunks_iter()
+        .filter(|&n| selection.contains(n))
+        .collect::<Vec<_>>();
+    fs::create_dir_all(output)?;
+    let progress = stylized_progress_bar(chunks.len() as u64);
+    chunks
+        .into_par_iter()
+        .map(|n| {
+            let png = fetcher.fetch_raw(n)?;
+            if !png.is_empty() {
+                fs::write(new_chunk_file(output, n, "png"), png)?;
+            }
+            progress.inc(1);
+            Ok(())
+        })
+        .collect::<anyhow::Result<()>>()?;
+    progress.finish();
+    Ok(())
+}
+
+/// Write the entries of `diff` selected by `selection` to `output`. References no longer used
+/// are dropped from the metadata.
+///
+/// Returns the numbers of kept entries, of kept changed entries and of all entries.
+fn filter_diff<R: Read + Seek>(
+    mut diff_file: diff::DiffFile<R>,
+    output: &Path,
+    selection: &ChunkSelection,
+) -> anyhow::Result<(usize, usize, usize)> {
+    let entries = diff_file
+        .read_index()?
+        .into_iter()
+        .filter(|e| selection.contains((e.x, e.y)))
+        .collect::<Vec<_>>();
+    let mut references = Vec::new();
+    for e in entries.iter().filter(|e| e.reference.is_some()) {
+        let name = diff_file.referenced_name(e)?;
+        if !references.contains(name) {
+            references.push(name.clone());
+        }
+    }
+    // keep the original order
+    references.sort_by_key(|x| diff_file.metadata.references.iter().position(|r| r == x));
+    let mut region = diff_file.metadata.region.clone();
+    if !selection.is_empty() {
+        region.push(selection.clone());
+    }
+
+    let metadata = diff::Metadata {
+        references: references.clone(),
+        checksum_algorithm: diff_file.metadata.checksum_algorithm,
+        shard: diff_file.metadata.shard.clone(),
+        region,
+    };
+    let temp_file = NamedTempFile::new_in(parent_dir(output))?;
+    let mut writer = diff::DiffFileWriter::create(
+        File::create_buffered(temp_file.as_ref())?,
+        metadata,
+        diff::VERSION,
+    )?;
+    for e in &entries {
+        let reference = shard::map_reference(&diff_file, e, &references)?;
+        writer.copy_entry(&mut diff_file, e, reference)?;
+    }
+    writer.finalize()?;
+    temp_file.persist(output)?;
+    Ok((
+        entries.len(),
+        entries.iter().filter(|x| x.is_changed()).count(),
+        diff_file.entry_count as usize,
+    ))
+}
+
+/// Re-apply the diff file to `base` in memory and compare each chunk with `new`.
+///
+/// Reference entries are resolved by replaying `history`, which is required if there are any.
+fn verify_diff(
+    diff: &Path,
+    base: &(dyn ChunkFetcher + Sync),
+    new: &(dyn ChunkFetcher + Sync),
+    history: Option<&ChainHistory>,
+) -> anyhow::Result<()> {
+    let mut diff_file = diff::DiffFile::open(File::open_buffered(diff)?)?;
+    diff_file.verify_header_and_index()?;
+    let algorithm = diff_file.metadata.checksum_algorithm;
+    let entries = diff_file.read_index()?;
+    let expected_count = diff_file.metadata.shard.as_ref().map_or_else(
+        || new.chunks_len(),
+        |shard| new.chunks_iter().filter(|&n| shard.contains(n)).count(),
+    );
+    if entries.len() != expected_count {
+        yeet!(anyhow::anyhow!(
+            "Chunk count not matched: {} vs {expected_count}",
+            entries.len(),
+        ));
+    }
+
+    let progress = stylized_progress_bar(entries.len() as u64);
+    let failure = entries
+        .par_iter()
+        .map_init(
+            || {
+                (
+                    File::open_buffered(diff),
+                    chunk_buf!(),
+                    chunk_buf!(),
+                    chunk_buf!(),
+                )
+            },
+            |(reader, base_buf, new_buf, diff_buf), e| {
+                let n = (e.x, e.y);
+                let result: anyhow::Result<()> = try {
+                    let reader = reader.as_mut().map_err(|e| anyhow::anyhow!("{e}"))?;
+                    if !new.fetch(n, new_buf)? {
+                        yeet!(anyhow::anyhow!("Chunk is not in the new snapshot"));
+                    }
+                    if e.reference.is_none() {
+                        if !base.fetch(n, base_buf)? {
+                            base_buf.fill(0);
+                        }
+                        if e.has_data() {
+                            reader.seek(SeekFrom::Start(e.pos))?;
+                            let mut payload = Vec::new();
+                            reader.take(e.len).read_to_end(&mut payload)?;
+                            diff::validate_payload_checksum(algorithm, e, &payload)?;
+                            zstd_decompress(Cursor::new(&payload), diff_buf)?;
+                            apply_chunk(base_buf, (&diff_buf[..]).try_into().unwrap());
+                        }
+                        if base_buf != new_buf {
+                            yeet!(anyhow::anyhow!("Reconstructed chunk differs"));
+                        }
+                    }
+                    validate_chunk_checksum(new_buf, e.checksum)?;
+                    progress.inc(1);
+                };
+                result.err().map(|err| ChunkProcessError {
+                    inner: err,
+                    chunk_number: n,
+                    diff_file: None,
+                })
+            },
+        )
+        .find_map_first(|x| x);
+    progress.finish();
+    if let Some(e) = failure {
+        yeet!(anyhow::anyhow!(e));
+    }
+
+    let mut references = Vec::new();
+    for e in entries.iter().filter(|e| e.reference.is_some()) {
+        references.push(((e.x, e.y), diff_file.referenced_name(e)?.clone()));
+    }
+    if !references.is_empty() {
+        let Some(history) = history else {
+            yeet!(anyhow::anyhow!(
+                "Reference entries can't be verified without the base snapshot of the chain"
+            ));
+        };
+        verify_references(history, &references, new)?;
+    }
+    Ok(())
+}
+
+/// Replay `history` for the chunks of `references` (chunk, referenced diff), and compare the
+/// state of each chunk after its referenced diff with `new`.
+fn verify_references(
+    history: &ChainHistory,
+    references: &[(ChunkNumber, Iso8601Name)],
+    new: &dyn ChunkFetcher,
+) -> anyhow::Result<()> {
+    info!("Resolving {} reference entries...", references.len());
+    let names = history.source.name_iter().cloned().collect::<Vec<_>>();
+    let mut replay = ChainReplay::for_chunks(
+        Arc::clone(&history.base),
+        Arc::clone(&history.source),
+        names,
+        references.iter().map(|x| x.0),
+    )?;
+    let (mut state, mut new_buf) = (chunk_buf!(), chunk_buf!());
+    while replay.applied() < replay.names().len() {
+        replay.step()?;
+        let current = replay.current().unwrap();
+        for (n, _) in references.iter().filter(|(_, r)| r == current) {
+            if !replay.fetch(*n, &mut state)? {
+                state.fill(0);
+            }
+            if !new.fetch(*n, &mut new_buf)? || state != new_buf {
+                yeet!(anyhow::anyhow!(
+                    "Reference of chunk {n:?} to {current} doesn't match the new snapshot"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn do_diff_for_directory(
+    base: PathBuf,
+    new: PathBuf,
+    output: PathBuf,
+    options: DiffOptions,
+) -> anyhow::Result<()> {
+    info!("Collecting files...");
+    let new_fetcher = DirChunkFetcher::new(&new, true)?;
+    let base_fetcher = DirChunkFetcher::new(&base, false)?;
+
+    do_diff(base_fetcher, new_fetcher, output, options)?;
+    Ok(())
+}
+
+fn do_diff_for_tar(
+    base: PathBuf,
+    new: PathBuf,
+    output: PathBuf,
+    mut options: DiffOptions,
+    layout: &ChunkPathLayout,
+) -> anyhow::Result<()> {
+    info!("Indexing 'base' tarball...");
+    let base_tar = TarChunkFetcher::new(&base, layout)?;
     info!("Indexing 'new' tarball...");
-    let new_tar = TarChunkFetcher::new(&new)?;
+    let new_tar = TarChunkFetcher::new(&new, layout)?;
+
+    // Chunk states saved by the diff leading to 'base' spare decoding its unchanged chunks.
+    if options.base_states.is_none() {
+        options.base_states = read_states_file(&base, layout).unwrap_or_else(|e| {
+            warn!("Failed to read chunk states of {}: {e}", base.display());
+            None
+        });
+    }
+    let new_states = do_diff(base_tar, new_tar, output, options)?;
+    if let Err(e) = write_states_file(&new, layout, &new_states) {
+        warn!("Failed to write chunk states of {}: {e}", new.display());
+    }
+    Ok(())
+}
+
+/// Diff two snapshots of a diff chain, both reconstructed in memory.
+fn do_diff_for_chain(
+    base_snapshot: &Path,
+    diff_source: &[PathBuf],
+    from: Option<&str>,
+    to: Option<&str>,
+    output: PathBuf,
+    mut options: DiffOptions,
+    layout: &ChunkPathLayout,
+) -> anyhow::Result<()> {
+    info!("Collecting diff files...");
+    let source = open_diff_source(diff_source)?;
+    let to = to.map_or_else(|| source.last(), Into::into);
+    if !source.contains(&to) {
+        yeet!(anyhow::anyhow!(
+            "Cannot find snapshot {to} from diff sources"
+        ));
+    }
+    if let Some(from) = from
+        && !source.range_iter(&source.first(), &to).any(|x| x == from)
+    {
+        yeet!(anyhow::anyhow!(
+            "Snapshot {from} is not in the chain before {to}"
+        ));
+    }
+    if from == Some(&to) {
+        yeet!(anyhow::anyhow!("'from' and 'to' are the same snapshot"));
+    }
+
+    info!("Reading base snapshot...");
+    let base_fetcher = open_chunk_fetcher(base_snapshot, true, layout)?;
+    info!("Scanning references...");
+    let names = source.range_iter(&source.first(), &to).collect::<Vec<_>>();
+    let mut replay = ChainReplay::new(base_fetcher, source, names)?;
 
-    do_diff(base_tar, new_tar, output)?;
+    info!("Replaying diff chain...");
+    let pb = stylized_progress_bar(replay.names().len() as u64);
+    if let Some(from) = from {
+        replay.replay_until(from, Some(&pb))?;
+    }
+    let from_fetcher = ChainChunkFetcher::new(replay.clone());
+    replay.replay_until(&to, Some(&pb))?;
+    pb.finish();
+    options.region = replay.region().to_vec();
+    let to_fetcher = ChainChunkFetcher::new(replay);
+
+    do_diff(from_fetcher, to_fetcher, output, options)?;
     Ok(())
 }
 
-mod apply {
-    use crate::cli::ApplyCmd;
+mod validate {
+    use indicatif::ProgressBar;
+    use rayon::prelude::*;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::PathBuf;
+    use wplace_tools::diff::{DiffFile, check_payload, is_diff_file};
+    use wplace_tools::{open_diff_source, stylized_progress_bar};
+
+    /// Max problems printed for each file
+    const MAX_PRINTED_PROBLEMS: usize = 20;
+
+    struct Report {
+        version: u16,
+        entry_count: u32,
+        problems: Vec<String>,
+    }
+
+    impl Report {
+        fn print(&self, name: &str) {
+            let integrity_note = match self.version {
+                4 => ", no integrity data",
+                _ => "",
+            };
+            if self.problems.is_empty() {
+                println!(
+                    "{name}: OK (version {}, {} entries{integrity_note})",
+                    self.version, self.entry_count
+                );
+                return;
+            }
+            println!(
+                "{name}: {} problem(s) (version {}, {} entries{integrity_note})",
+                self.problems.len(),
+                self.version,
+                self.entry_count
+            );
+            for p in self.problems.iter().take(MAX_PRINTED_PROBLEMS) {
+                println!("  - {p}");
+            }
+            if self.problems.len() > MAX_PRINTED_PROBLEMS {
+                println!(
+                    "  ... and {} more",
+                    self.problems.len() - MAX_PRINTED_PROBLEMS
+                );
+            }
+        }
+    }
+
+    /// Validate one diff file. `open` creates an independent reader of it.
+    fn validate_diff<R: Read + Seek>(
+        open: impl Fn() -> anyhow::Result<R> + Sync,
+        pb: Option<&ProgressBar>,
+    ) -> anyhow::Result<Report> {
+        let mut diff_file = DiffFile::open(open()?)?;
+        let mut problems = Vec::new();
+        if diff_file.version >= 5
+            && let Err(e) = diff_file.verify_header_and_index()
+        {
+            problems.push(e.to_string());
+        }
+        let entries = diff_file.read_index()?;
+        problems.extend(diff_file.check_structure(&entries)?);
+        if let Some(pb) = pb {
+            pb.set_length(entries.len() as u64);
+        }
+
+        let algorithm = diff_file.metadata.checksum_algorithm;
+        let payload_problems = entries
+            .par_iter()
+            .map_init(
+                || (open(), Vec::new()),
+                |(reader, buf), e| {
+                    if let Some(pb) = pb {
+                        pb.inc(1);
+                    }
+                    if !e.has_data() {
+                        return None;
+                    }
+                    let result: anyhow::Result<()> = try {
+                        let reader = reader.as_mut().map_err(|e| anyhow::anyhow!("{e}"))?;
+                        reader.seek(SeekFrom::Start(e.pos))?;
+                        let mut payload = Vec::new();
+                        reader.take(e.len).read_to_end(&mut payload)?;
+                        check_payload(algorithm, e, &payload, buf)?;
+                    };
+                    result
+                        .err()
+                        .map(|err| format!("Chunk {:?}: {err}", (e.x, e.y)))
+                },
+            )
+            .flatten()
+            .collect::<Vec<_>>();
+        problems.extend(payload_problems);
+
+        Ok(Report {
+            version: diff_file.version,
+            entry_count: diff_file.entry_count,
+            problems,
+        })
+    }
+
+    /// Returns whether all diff files are valid.
+    pub fn main(paths: &[PathBuf]) -> anyhow::Result<bool> {
+        if let [path] = paths
+            && is_diff_file(path)
+        {
+            let pb = stylized_progress_bar(0);
+            let report = validate_diff(|| Ok(File::open_buffered(path)?), Some(&pb))?;
+            pb.finish();
+            report.print(&path.display().to_string());
+            return Ok(report.problems.is_empty());
+        }
+
+        let source = open_diff_source(paths)?;
+        let names = source.name_iter().cloned().collect::<Vec<_>>();
+        let pb = stylized_progress_bar(names.len() as u64);
+        let reports = names
+            .par_iter()
+            .map(|name| {
+                let report =
+                    validate_diff(|| source.reader(name), None).unwrap_or_else(|e| Report {
+                        version: 0,
+                        entry_count: 0,
+                        problems: vec![e.to_string()],
+                    });
+                pb.inc(1);
+                report
+            })
+            .collect::<Vec<_>>();
+        pb.finish();
+
+        let mut failed = 0_usize;
+        for (name, report) in names.iter().zip(&reports) {
+            report.print(name);
+            if !report.problems.is_empty() {
+                failed += 1;
+            }
+        }
+        println!("{} files tested, {} failed.", names.len(), failed);
+        Ok(failed == 0)
+    }
+}
+
+mod verify_chain {
+    use log::info;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use wplace_tools::chain::{ChainReplay, StepStats, open_chain_base};
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{ChunkProcessError, open_diff_source, stylized_progress_bar};
+
+    fn print_stats(stats: &StepStats, diffs: usize) {
+        println!("Diffs applied: {diffs}");
+        println!("Chunks in the last snapshot: {}", stats.chunks);
+        if stats.base_checked > 0 {
+            println!("Base chunks checked: {}", stats.base_checked);
+        }
+        println!("Chunks applied with diff data: {}", stats.changed);
+        println!("Chunks restored by reference: {}", stats.references);
+        println!("Chunks added: {}", stats.added);
+        println!("Chunks deleted: {}", stats.deleted);
+        println!("Diff data read: {} bytes", stats.payload_bytes);
+        println!("Mutated pixels: {}", stats.mutated_pixels);
+    }
+
+    /// Returns whether the whole chain is reconstructed correctly.
+    pub fn main(
+        base: &Path,
+        diff_source: &[PathBuf],
+        skip_base: bool,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<bool> {
+        let start = Instant::now();
+        info!("Collecting diff files...");
+        let source = open_diff_source(diff_source)?;
+        let names = source.name_iter().cloned().collect::<Vec<_>>();
+        info!("Reading base snapshot...");
+        let base_fetcher = open_chain_base(base, &*source, &names, layout)?;
+
+        info!("Scanning references...");
+        let mut replay = ChainReplay::new(base_fetcher, Arc::clone(&source), names)?;
+        replay.validate_base = !skip_base;
+
+        let total = replay.names().len();
+        let mut stats = StepStats::default();
+        let pb = stylized_progress_bar(total as u64);
+        while !replay.is_finished() {
+            let name = replay.names()[replay.applied()].clone();
+            pb.set_message(name.clone());
+            match replay.step() {
+                Ok(s) => stats.accumulate(&s),
+                Err(e) => {
+                    pb.abandon();
+                    println!(
+                        "Chain broken at diff {name} ({}/{total})",
+                        replay.applied() + 1
+                    );
+                    match e.downcast_ref::<ChunkProcessError>() {
+                        Some(e) => {
+                            println!("Chunk: {:?}", e.chunk_number);
+                            println!("Error: {}", e.inner);
+                        }
+                        None => println!("Error: {e}"),
+                    }
+                    println!();
+                    println!("Before the failure:");
+                    print_stats(&stats, replay.applied());
+                    return Ok(false);
+                }
+            }
+            pb.inc(1);
+        }
+        pb.finish();
+
+        println!("Chain OK: {} -> {}", base.display(), source.last());
+        print_stats(&stats, total);
+        println!("Elapsed: {:.1?}", start.elapsed());
+        Ok(true)
+    }
+}
+
+mod thin {
+    use crate::{DiffOptions, do_diff, rebase};
+    use anyhow::anyhow;
+    use lazy_regex::regex;
+    use log::info;
+    use std::collections::{BTreeSet, HashSet};
+    use std::fs;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use wplace_tools::chain::{ChainChunkFetcher, ChainReplay, open_chain_base};
+    use wplace_tools::diff::DiffFile;
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{
+        DirDiffFilesCollector, Iso8601Name, name_timestamp, parent_dir, quick_capture,
+        stylized_progress_bar,
+    };
+    use yeet_ops::yeet;
+
+    struct Rule {
+        /// Max age in milliseconds; None for all the rest
+        max_age: Option<i64>,
+        /// Bucket length in milliseconds; None to keep every diff
+        resolution: Option<i64>,
+    }
+
+    pub struct Policy {
+        rules: Vec<Rule>,
+    }
+
+    pub fn parse_duration(s: &str) -> anyhow::Result<i64> {
+        let c = quick_capture(s, regex!(r"^(\d+)([smhdw])$"))
+            .ok_or_else(|| anyhow!("Malformed duration: {s}"))?;
+        let unit = match c[1] {
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 3600 * 1000,
+            "d" => 24 * 3600 * 1000,
+            "w" => 7 * 24 * 3600 * 1000,
+            _ => unreachable!(),
+        };
+        Ok(c[0].parse::<i64>()? * unit)
+    }
+
+    impl Policy {
+        pub fn parse(s: &str) -> anyhow::Result<Self> {
+            let mut rules = Vec::new();
+            for rule in s.split(',').map(str::trim) {
+                let (age, resolution) = rule
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Malformed rule: {rule}"))?;
+                let max_age = match age {
+                    "*" => None,
+                    _ => Some(parse_duration(age)?),
+                };
+                let resolution = match resolution {
+                    "all" => None,
+                    _ => Some(parse_duration(resolution)?),
+                };
+                if resolution == Some(0) {
+                    yeet!(anyhow!("Resolution can't be zero: {rule}"));
+                }
+                if let Some(last) = rules.last().map(|x: &Rule| x.max_age)
+                    && (last.is_none() || last >= max_age && max_age.is_some())
+                {
+                    yeet!(anyhow!("Rules must be ordered by age: {rule}"));
+                }
+                rules.push(Rule {
+                    max_age,
+                    resolution,
+                });
+            }
+            Ok(Self { rules })
+        }
+
+        /// Bucket of a snapshot taken at `time`, `age` old. Diffs outside all rules are kept.
+        fn bucket(&self, time: i64, age: i64, index: usize) -> (usize, i64) {
+            let rule = self
+                .rules
+                .iter()
+                .position(|r| r.max_age.is_none_or(|x| age < x));
+            match rule.map(|i| (i, self.rules[i].resolution)) {
+                Some((i, Some(resolution))) => (i, time.div_euclid(resolution)),
+                Some((i, None)) => (i, index as i64),
+                None => (self.rules.len(), index as i64),
+            }
+        }
+    }
+
+    /// Group consecutive diffs of `names` into buckets.
+    fn collect_buckets(
+        names: &[Iso8601Name],
+        policy: &Policy,
+    ) -> anyhow::Result<Vec<Vec<Iso8601Name>>> {
+        let times = names
+            .iter()
+            .map(|x| name_timestamp(x).ok_or_else(|| anyhow!("Malformed snapshot name: {x}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let newest = *times.last().unwrap();
+
+        let mut buckets: Vec<Vec<Iso8601Name>> = Vec::new();
+        let mut last_key = None;
+        for (i, (name, &time)) in names.iter().zip(&times).enumerate() {
+            let key = policy.bucket(time, newest - time, i);
+            match buckets.last_mut() {
+                Some(b) if last_key == Some(key) => b.push(name.clone()),
+                _ => buckets.push(vec![name.clone()]),
+            }
+            last_key = Some(key);
+        }
+        Ok(buckets)
+    }
+
+    pub fn main(
+        base: &Path,
+        diff_dir: &Path,
+        policy: &Policy,
+        dry_run: bool,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<()> {
+        if !diff_dir.is_dir() {
+            yeet!(anyhow!("Diff source must be a directory"));
+        }
+        let collector = Arc::new(DirDiffFilesCollector::new([diff_dir])?);
+        let names = collector.names.keys().cloned().collect::<Vec<_>>();
+        let buckets = collect_buckets(&names, policy)?;
+
+        let removed = buckets
+            .iter()
+            .flat_map(|b| &b[..(b.len() - 1)])
+            .cloned()
+            .collect::<HashSet<_>>();
+        // kept diffs referencing removed ones
+        let mut rewritten = BTreeSet::new();
+        for b in buckets.iter().filter(|b| b.len() == 1) {
+            let diff_file = DiffFile::open(File::open_buffered(collector.path(&b[0]).unwrap())?)?;
+            if diff_file
+                .metadata
+                .references
+                .iter()
+                .any(|r| removed.contains(r))
+            {
+                rewritten.insert(b[0].clone());
+            }
+        }
+
+        for b in buckets.iter().filter(|b| b.len() > 1) {
+            println!(
+                "{}: combine {} diffs ({} .. {})",
+                b.last().unwrap(),
+                b.len(),
+                b[0],
+                b.last().unwrap()
+            );
+        }
+        for name in &rewritten {
+            println!("{name}: rewrite references to removed diffs");
+        }
+        println!(
+            "{} diffs -> {} diffs",
+            names.len(),
+            names.len() - removed.len()
+        );
+        if dry_run || removed.is_empty() {
+            return Ok(());
+        }
+
+        // the last diff that has to be replayed
+        let end = buckets
+            .iter()
+            .filter(|b| b.len() > 1 || rewritten.contains(&b[0]))
+            .filter_map(|b| names.iter().position(|x| x == b.last().unwrap()))
+            .max()
+            .unwrap();
+
+        info!("Reading base snapshot...");
+        let base_fetcher = open_chain_base(base, &*collector, &names, layout)?;
+        info!("Scanning references...");
+        let mut replay = ChainReplay::new(
+            base_fetcher,
+            Arc::clone(&collector) as _,
+            names[..=end].to_vec(),
+        )?;
+
+        // New files are written aside first, and replace the old ones at last.
+        let mut combined = Vec::new();
+        let mut rewrites = Vec::new();
+        for b in &buckets {
+            let last = b.last().unwrap();
+            if replay.is_finished() {
+                break;
+            }
+            if b.len() == 1 {
+                if rewritten.contains(last) {
+                    info!("Rewriting {last}...");
+                    let path = collector.path(last).unwrap();
+                    rewrites.push((
+                        rebase::rewrite_diff(&mut replay, last, &path, &removed)?,
+                        path,
+                    ));
+                }
+                continue;
+            }
+
+            info!("Combining {} diffs into {last}...", b.len());
+            let first_pos = names.iter().position(|x| x == &b[0]).unwrap();
+            while replay.applied() < first_pos {
+                replay.step()?;
+            }
+            let from = ChainChunkFetcher::new(replay.clone());
+            let pb = stylized_progress_bar(b.len() as u64);
+            replay.replay_until(last, Some(&pb))?;
+            pb.finish_and_clear();
+            let to = ChainChunkFetcher::new(replay.clone());
+
+            let last_path = collector.path(last).unwrap();
+            let options = DiffOptions {
+                checksum_algorithm: DiffFile::open(File::open_buffered(&last_path)?)?
+                    .metadata
+                    .checksum_algorithm,
+                region: replay.region().to_vec(),
+                ..Default::default()
+            };
+            // `do_diff` replaces the placeholder file, which is removed if anything fails.
+            let output = NamedTempFile::new_in(parent_dir(&last_path))?;
+            do_diff(from, to, output.path().into(), options)?;
+            combined.push((output, last_path));
+        }
+
+        info!("Replacing diff files...");
+        for (temp_file, path) in rewrites {
+            temp_file.persist(path)?;
+        }
+        for (output, path) in combined {
+            output.persist(path)?;
+        }
+        for name in &removed {
+            fs::remove_file(collector.path(name).unwrap())?;
+        }
+        info!("Done.");
+        Ok(())
+    }
+}
+
+mod rebase {
+    use crate::{diff_chunk, shard, write_snapshot_tar};
+    use anyhow::anyhow;
     use log::{info, warn};
-    use once_cell::sync::Lazy;
-    use rayon::iter::{ParallelBridge, ParallelIterator};
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
     use std::fs::File;
-    use std::io;
     use std::io::{Cursor, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+    use wplace_tools::chain::{ChainChunkFetcher, ChainReplay, STATE_ZSTD_LEVEL, open_chain_base};
+    use wplace_tools::diff::{ChangeStats, DiffFile, DiffFileWriter, Metadata};
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{
+        ChunkNumber, ChunkSelection, DIFF_DATA_ZSTD_COMPRESSION_LEVEL, DirDiffFilesCollector,
+        Iso8601Name, chunk_buf, diff, parent_dir, stylized_progress_bar, zstd_decompress,
+    };
+    use yeet_ops::yeet;
+
+    pub enum OldDiffs {
+        MoveTo(PathBuf),
+        Archive(PathBuf),
+    }
+
+    pub fn main(
+        base: &Path,
+        diff_dir: &Path,
+        at: &str,
+        output: Option<PathBuf>,
+        old_diffs: OldDiffs,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<()> {
+        if !diff_dir.is_dir() {
+            yeet!(anyhow!("Diff source must be a directory"));
+        }
+        let collector = Arc::new(DirDiffFilesCollector::new([diff_dir])?);
+        if !collector.names.contains_key(at) {
+            yeet!(anyhow!("Cannot find snapshot {at} from the diff directory"));
+        }
+        let names = collector.names.keys().cloned().collect::<Vec<_>>();
+        let split = names.iter().position(|x| x == at).unwrap() + 1;
+        let (old, kept) = names.split_at(split);
+        let old_set = old.iter().cloned().collect::<HashSet<_>>();
+        let output = output.unwrap_or_else(|| parent_dir(base).join(format!("{at}.tar")));
+        if output.exists() {
+            yeet!(anyhow!("Output exists: {}", output.display()));
+        }
+
+        // Diffs after `at` referencing the old ones; references only go back a few diffs
+        let mut affected = Vec::new();
+        for name in kept {
+            let diff_file = DiffFile::open(File::open_buffered(collector.path(name).unwrap())?)?;
+            if diff_file
+                .metadata
+                .references
+                .iter()
+                .any(|r| old_set.contains(r))
+            {
+                affected.push(name.clone());
+            }
+        }
+        let replay_end = affected.last().map_or(at, |x| x.as_str());
+
+        info!("Reading base snapshot...");
+        let base_fetcher = open_chain_base(base, &*collector, &names, layout)?;
+        info!("Scanning references...");
+        let replay_names = names[..=names.iter().position(|x| x == replay_end).unwrap()].to_vec();
+        let mut replay = ChainReplay::new(base_fetcher, Arc::clone(&collector) as _, replay_names)?;
+
+        info!("Reconstructing snapshot {at}...");
+        let pb = stylized_progress_bar(split as u64);
+        replay.replay_until(at, Some(&pb))?;
+        pb.finish();
+
+        info!("Writing new base snapshot to {}...", output.display());
+        write_snapshot_tar(
+            &ChainChunkFetcher::new(replay.clone()),
+            at,
+            &output,
+            &ChunkSelection::default(),
+        )?;
+
+        // Write the rewritten diffs aside first; originals are only replaced when all succeed.
+        let mut rewritten = Vec::new();
+        for name in &affected {
+            info!("Rewriting {name}, which references diffs up to {at}...");
+            let path = collector.path(name).unwrap();
+            rewritten.push((rewrite_diff(&mut replay, name, &path, &old_set)?, path));
+        }
+        for (temp_file, path) in rewritten {
+            temp_file.persist(path)?;
+        }
+
+        let old_paths = old
+            .iter()
+            .map(|x| collector.path(x).unwrap())
+            .collect::<Vec<_>>();
+        match old_diffs {
+            OldDiffs::MoveTo(dir) => {
+                info!("Moving {} old diffs to {}...", old.len(), dir.display());
+                fs::create_dir_all(&dir)?;
+                for p in &old_paths {
+                    move_file(p, &dir.join(p.file_name().unwrap()))?;
+                }
+            }
+            OldDiffs::Archive(tar) => {
+                info!("Archiving {} old diffs to {}...", old.len(), tar.display());
+                let temp_file = NamedTempFile::new_in(parent_dir(&tar))?;
+                let mut builder = tar::Builder::new(File::create_buffered(temp_file.as_ref())?);
+                for p in &old_paths {
+                    builder.append_path_with_name(p, p.file_name().unwrap())?;
+                }
+                builder.into_inner()?.flush()?;
+                temp_file.persist(&tar)?;
+                for p in &old_paths {
+                    fs::remove_file(p)?;
+                }
+            }
+        }
+        if kept.is_empty() {
+            warn!("No diffs remain in the chain");
+        }
+        info!(
+            "Done. New base: {}; the old base snapshot {} is no longer needed by the chain.",
+            output.display(),
+            base.display()
+        );
+        Ok(())
+    }
+
+    /// Apply diff `name` with `replay`, and rewrite its reference entries pointing to `old`
+    /// as diff data. Returns the rewritten diff file.
+    pub fn rewrite_diff(
+        replay: &mut ChainReplay,
+        name: &str,
+        path: &Path,
+        old: &HashSet<Iso8601Name>,
+    ) -> anyhow::Result<NamedTempFile> {
+        let pos = replay.names().iter().position(|x| x == name).unwrap();
+        while replay.applied() < pos {
+            replay.step()?;
+        }
+
+        let mut diff_file = DiffFile::open(File::open_buffered(path)?)?;
+        let metadata = diff_file.metadata.clone();
+        let entries = diff_file.read_index()?;
+        let references = metadata
+            .references
+            .iter()
+            .filter(|x| !old.contains(*x))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Reference entries to `old` become diff data computed from the states before and
+        // after this diff
+        let mut resolved = HashMap::new();
+        let mut buf = chunk_buf!();
+        for e in entries.iter().filter(|e| e.reference.is_some()) {
+            if old.contains(diff_file.referenced_name(e)?) {
+                if !replay.fetch((e.x, e.y), &mut buf)? {
+                    buf.fill(0);
+                }
+                resolved.insert((e.x, e.y), zstd::encode_all(&buf[..], STATE_ZSTD_LEVEL)?);
+            }
+        }
+        replay.step()?;
+        let mut new_buf = chunk_buf!();
+        let mut computed: HashMap<ChunkNumber, (Vec<u8>, ChangeStats)> = HashMap::new();
+        for (n, prev) in resolved {
+            zstd_decompress(Cursor::new(&prev), &mut buf)?;
+            if !replay.fetch(n, &mut new_buf)? {
+                new_buf.fill(0);
+            }
+            diff_chunk(&mut buf, &new_buf);
+            let stats = ChangeStats::from_diff_data(&buf);
+            let data = zstd::encode_all(&buf[..], DIFF_DATA_ZSTD_COMPRESSION_LEVEL)?;
+            computed.insert(n, (data, stats));
+        }
+
+        let temp_file = NamedTempFile::new_in(parent_dir(path))?;
+        let new_metadata = Metadata {
+            references: references.clone(),
+            checksum_algorithm: metadata.checksum_algorithm,
+            shard: None,
+            region: metadata.region,
+        };
+        let mut writer = DiffFileWriter::create(
+            File::create_buffered(temp_file.as_ref())?,
+            new_metadata,
+            diff::VERSION,
+        )?;
+        for e in &entries {
+            let n = (e.x, e.y);
+            match computed.get(&n) {
+                Some((data, stats)) => writer.add_entry(
+                    n,
+                    Some(data),
+                    e.checksum,
+                    e.state_digest.unwrap_or_default(),
+                    *stats,
+                )?,
+                None => {
+                    let reference = shard::map_reference(&diff_file, e, &references)?;
+                    writer.copy_entry(&mut diff_file, e, reference)?;
+                }
+            }
+        }
+        writer.finalize()?;
+        Ok(temp_file)
+    }
+
+    fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+        if to.exists() {
+            yeet!(anyhow!("Destination exists: {}", to.display()));
+        }
+        if fs::rename(from, to).is_err() {
+            // probably across file systems
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+        }
+        Ok(())
+    }
+}
+
+mod ingest {
+    use crate::cli::IngestArgs;
+    use crate::{ChainHistory, DiffOptions, collect_base_states, do_diff};
+    use anyhow::anyhow;
+    use log::info;
+    use std::ffi::OsStr;
+    use std::fmt::{Display, Formatter};
+    use std::fs;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use wplace_tools::chain::{ChainChunkFetcher, ChainReplay, open_chain_base};
+    use wplace_tools::diff::{DiffFile, RecentStates};
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::tar::is_tarball;
+    use wplace_tools::{
+        ChunkFetcher, DiffFilesCollector, DirDiffFilesCollector, Iso8601Name, TarChunkFetcher,
+        extract_datetime, open_chunk_fetcher, parent_dir, stylized_progress_bar,
+    };
+    use yeet_ops::yeet;
+
+    /// Where the state of the previous snapshot comes from
+    pub enum Parent {
+        Snapshot(PathBuf),
+        Chain,
+    }
+
+    impl Display for Parent {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Snapshot(p) => write!(f, "{}", p.display()),
+                Self::Chain => write!(f, "reconstructed from the chain"),
+            }
+        }
+    }
+
+    pub struct Summary {
+        pub name: Iso8601Name,
+        pub previous: Iso8601Name,
+        pub parent: Parent,
+        pub output: PathBuf,
+        pub chunks: usize,
+        pub changed: usize,
+        pub size: u64,
+    }
+
+    impl Display for Summary {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{} -> {}: {}/{} chunks changed, {} bytes written to {} (parent: {})",
+                self.previous,
+                self.name,
+                self.changed,
+                self.chunks,
+                self.size,
+                self.output.display(),
+                self.parent
+            )
+        }
+    }
+
+    /// Diff snapshot tarball `new` against the last snapshot of the chain, and add the diff to
+    /// the chain.
+    pub fn ingest(
+        new: &Path,
+        args: &IngestArgs,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<Summary> {
+        let name = new
+            .file_name()
+            .and_then(extract_datetime)
+            .ok_or_else(|| anyhow!("Can't get the snapshot name of {}", new.display()))?;
+        if !is_tarball(new) {
+            yeet!(anyhow!("New snapshot must be a tarball"));
+        }
+        info!("Indexing {}...", new.display());
+        let new_fetcher = TarChunkFetcher::new(new, layout)?;
+        if extract_datetime(new_fetcher.root_name()).as_ref() != Some(&name) {
+            yeet!(anyhow!(
+                "Root directory '{}' of the tarball doesn't match its file name {name}",
+                new_fetcher.root_name()
+            ));
+        }
+
+        fs::create_dir_all(&args.chain)?;
+        let has_diffs = fs::read_dir(&args.chain)?.any(|x| {
+            x.is_ok_and(|x| {
+                x.path()
+                    .extension()
+                    .map(|x| x.to_ascii_lowercase())
+                    .as_deref()
+                    == Some(OsStr::new("diff"))
+            })
+        });
+        let source = match has_diffs {
+            true => Some(Arc::new(DirDiffFilesCollector::new([&args.chain])?)),
+            false => None,
+        };
+        let base_name = args
+            .base_snapshot
+            .as_ref()
+            .and_then(|x| x.file_name().and_then(extract_datetime));
+        let previous = match (&source, &base_name) {
+            (Some(s), _) => s.last(),
+            (None, Some(b)) => b.clone(),
+            (None, None) => {
+                yeet!(anyhow!(
+                    "The chain has no diffs yet; a named base snapshot is required"
+                ));
+            }
+        };
+        if source.as_ref().is_some_and(|x| x.contains(&name)) {
+            yeet!(anyhow!("Snapshot {name} is already in the chain"));
+        }
+        if name <= previous {
+            yeet!(anyhow!(
+                "Snapshot {name} is not newer than the chain head {previous}"
+            ));
+        }
+        let output = args.chain.join(format!("{name}.diff"));
+        if output.exists() {
+            yeet!(anyhow!("Output exists: {}", output.display()));
+        }
+
+        let snapshots_dir = args.snapshots.as_deref().unwrap_or_else(|| parent_dir(new));
+        let (parent, parent_fetcher) = match find_snapshot(snapshots_dir, &previous, new)? {
+            Some(path) => {
+                info!("Reading previous snapshot {}...", path.display());
+                let fetcher = open_chunk_fetcher(&path, false, layout)?;
+                (Parent::Snapshot(path), fetcher)
+            }
+            None if base_name.as_ref() == Some(&previous) => {
+                let path = args.base_snapshot.clone().unwrap();
+                info!("Reading base snapshot {}...", path.display());
+                let fetcher = open_chunk_fetcher(&path, false, layout)?;
+                (Parent::Snapshot(path), fetcher)
+            }
+            None => {
+                let (Some(source), Some(base)) = (&source, &args.base_snapshot) else {
+                    yeet!(anyhow!(
+                        "Previous snapshot {previous} is not found; a base snapshot is required to reconstruct it"
+                    ));
+                };
+                info!(
+                    "Previous snapshot {previous} is not found; reconstructing it from the chain..."
+                );
+                let names = source.names.keys().cloned().collect::<Vec<_>>();
+                let base_fetcher = open_chain_base(base, &**source, &names, layout)?;
+                let mut replay = ChainReplay::new(base_fetcher, Arc::clone(source) as _, names)?;
+                let pb = stylized_progress_bar(replay.names().len() as u64);
+                replay.replay_until(&previous, Some(&pb))?;
+                pb.finish();
+                let fetcher: Arc<dyn ChunkFetcher + Send + Sync> =
+                    Arc::new(ChainChunkFetcher::new(replay));
+                (Parent::Chain, fetcher)
+            }
+        };
+
+        let recent_states = match (&source, args.references) {
+            (Some(source), true) => {
+                info!("Collecting recent chunk states from the chain...");
+                Some(RecentStates::collect(&**source, args.history_window)?)
+            }
+            _ => None,
+        };
+        let base_states = match &source {
+            Some(source) => Some(collect_base_states(&**source, &previous)?),
+            None => None,
+        };
+        let history = match (&source, &recent_states, args.verify) {
+            (Some(source), Some(_), true) => {
+                let Some(base) = &args.base_snapshot else {
+                    yeet!(anyhow!(
+                        "Verifying reference entries requires the base snapshot of the chain"
+                    ));
+                };
+                let names = source.names.keys().cloned().collect::<Vec<_>>();
+                Some(ChainHistory {
+                    base: open_chain_base(base, &**source, &names, layout)?,
+                    source: Arc::clone(source) as _,
+                })
+            }
+            _ => None,
+        };
+        let options = DiffOptions {
+            recent_states,
+            base_states,
+            checksum_algorithm: args.checksum,
+            verify: args.verify,
+            history,
+            shard: None,
+            region: Vec::new(),
+        };
+        do_diff(parent_fetcher, new_fetcher, output.clone(), options)?;
+
+        let mut diff_file = DiffFile::open(File::open_buffered(&output)?)?;
+        let index = diff_file.read_index()?;
+        let summary = Summary {
+            name,
+            previous,
+            parent,
+            chunks: index.len(),
+            changed: index.iter().filter(|x| x.is_changed()).count(),
+            size: fs::metadata(&output)?.len(),
+            output,
+        };
+        info!("Ingested {summary}");
+        Ok(summary)
+    }
+
+    /// Find the full snapshot `name` (tarball or folder) in `dir`, except `new` itself.
+    fn find_snapshot(dir: &Path, name: &str, new: &Path) -> anyhow::Result<Option<PathBuf>> {
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        for e in fs::read_dir(dir)? {
+            let path = e?.path();
+            if path.file_name().and_then(extract_datetime).as_deref() != Some(name)
+                || path.file_name() == new.file_name()
+            {
+                continue;
+            }
+            if (is_tarball(&path) && path.is_file()) || path.is_dir() {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+}
+
+mod watch {
+    use crate::cli::IngestArgs;
+    use crate::ingest::{Parent, ingest};
+    use log::{error, info};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread::sleep;
+    use std::time::{Duration, SystemTime};
+    use wplace_tools::extract_datetime;
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::tar::is_tarball;
+
+    pub struct Options {
+        pub interval: Duration,
+        /// Minimum time since the last modification of a tarball before it's ingested
+        pub settle: Duration,
+        /// Delete ingested tarballs in the incoming directory
+        pub delete: bool,
+        pub once: bool,
+    }
+
+    /// (size, modification time) of a file
+    type Stamp = (u64, SystemTime);
+
+    pub fn main(
+        incoming: &Path,
+        args: &IngestArgs,
+        options: &Options,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<()> {
+        info!(
+            "Watching {} for new snapshots; diffs go to {}",
+            incoming.display(),
+            args.chain.display()
+        );
+        let mut last_seen = HashMap::new();
+        let mut failed = HashMap::new();
+        loop {
+            let candidates = scan(incoming, args)?;
+            for (name, path) in &candidates {
+                let Ok(stamp) = stamp(path) else {
+                    // removed in the meantime
+                    break;
+                };
+                // a file is complete if it stays untouched for a while
+                let settled = stamp.1.elapsed().unwrap_or_default() >= options.settle
+                    && (options.once || last_seen.get(path) == Some(&stamp));
+                if !settled {
+                    // later snapshots have to wait for this one
+                    break;
+                }
+                if failed.get(path) == Some(&stamp) {
+                    // later snapshots are diffed against this one
+                    break;
+                }
+
+                info!("Ingesting {name}...");
+                match ingest(path, args, layout) {
+                    Ok(summary) => {
+                        failed.remove(path);
+                        if options.delete {
+                            // the parent is kept until the next snapshot is ingested
+                            if let Parent::Snapshot(p) = &summary.parent
+                                && p.parent() == path.parent()
+                                && p.is_file()
+                                && args.base_snapshot.as_deref() != Some(p.as_path())
+                            {
+                                info!("Deleting {}", p.display());
+                                for part in wplace_tools::tar::tarball_files(p)? {
+                                    fs::remove_file(part)?;
+                                }
+                                let index = wplace_tools::tar::index_path(p);
+                                if index.exists() {
+                                    fs::remove_file(index)?;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to ingest {}: {e:?}", path.display());
+                        // retried only if the file changes; later snapshots wait for it
+                        failed.insert(path.clone(), stamp);
+                        break;
+                    }
+                }
+            }
+            last_seen = candidates
+                .iter()
+                .filter_map(|(_, p)| Some((p.clone(), stamp(p).ok()?)))
+                .collect();
+
+            if options.once {
+                break;
+            }
+            sleep(options.interval);
+        }
+        Ok(())
+    }
+
+    /// Snapshot tarballs in `dir` that are not in the chain yet, in name order
+    fn scan(dir: &Path, args: &IngestArgs) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let base_name = args
+            .base_snapshot
+            .as_ref()
+            .and_then(|x| x.file_name().and_then(extract_datetime));
+        let mut list = Vec::new();
+        for e in fs::read_dir(dir)? {
+            let path = e?.path();
+            if !is_tarball(&path) || !path.is_file() {
+                continue;
+            }
+            let Some(name) = path.file_name().and_then(extract_datetime) else {
+                continue;
+            };
+            if args.chain.join(format!("{name}.diff")).exists() || Some(&name) == base_name.as_ref()
+            {
+                continue;
+            }
+            list.push((name, path));
+        }
+        list.sort();
+        Ok(list)
+    }
+
+    fn stamp(path: &Path) -> anyhow::Result<Stamp> {
+        let metadata = fs::metadata(path)?;
+        Ok((metadata.len(), metadata.modified()?))
+    }
+}
+
+mod inspect {
+    use crate::cli::LsSort;
+    use serde::Serialize;
+    use std::fs::File;
+    use std::io;
+    use std::io::{BufWriter, Cursor, Read, Write, stdout};
     use std::path::Path;
-    use std::process::exit;
-    use std::sync::Mutex;
-    use wplace_tools::diff::{DiffFile, IndexEntry};
-    use wplace_tools::indexed_png::{read_png_reader, write_chunk_png};
+    use wplace_tools::diff::{ChangeStats, DiffFile, Metadata};
+    use wplace_tools::{Iso8601Name, TilesRange, chunk_buf, zstd_decompress};
+
+    #[derive(Serialize)]
+    struct Info<'a> {
+        version: u16,
+        metadata: &'a Metadata,
+        total_chunks: usize,
+        changed_chunks: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mutated_pixels: Option<u64>,
+    }
+
+    pub fn show(diff: &Path, json: bool) -> anyhow::Result<()> {
+        let mut diff_file = DiffFile::open(File::open_buffered(diff)?)?;
+        let index = diff_file.read_index()?;
+        let info = Info {
+            version: diff_file.version,
+            metadata: &diff_file.metadata,
+            total_chunks: index.len(),
+            changed_chunks: index.iter().filter(|x| x.is_changed()).count(),
+            mutated_pixels: index.iter().map(|x| x.stats.map(|s| s.pixels as u64)).sum(),
+        };
+        if json {
+            println!("{}", serde_json::to_string(&info)?);
+            return Ok(());
+        }
+        println!("Version: {}", info.version);
+        println!("Metadata: {}", serde_json::to_string(info.metadata)?);
+        println!("Total chunks: {}", info.total_chunks);
+        println!("Changed chunks: {}", info.changed_chunks);
+        if let Some(p) = info.mutated_pixels {
+            println!("Mutated pixels: {p}");
+        }
+        Ok(())
+    }
+
+    #[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    enum State {
+        Unchanged,
+        Data,
+        Reference,
+    }
+
+    #[derive(Serialize)]
+    struct Row {
+        x: u16,
+        y: u16,
+        state: State,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reference: Option<Iso8601Name>,
+        checksum: u32,
+        /// Size of the compressed diff data
+        size: u64,
+        /// Number of mutated pixels
+        #[serde(skip_serializing_if = "Option::is_none")]
+        changes: Option<u32>,
+    }
+
+    pub struct LsOptions {
+        pub tiles_range: Option<TilesRange>,
+        pub changed_only: bool,
+        pub changes: bool,
+        pub sort: LsSort,
+        pub reverse: bool,
+        pub json: bool,
+    }
+
+    pub fn ls(diff: &Path, options: &LsOptions) -> anyhow::Result<()> {
+        let mut diff_file = DiffFile::open(File::open_buffered(diff)?)?;
+        let entries = diff_file.read_index()?;
+        let changes = options.changes || options.sort == LsSort::Changes;
+
+        let mut rows = Vec::new();
+        let mut buf = chunk_buf!();
+        for e in entries {
+            if options.tiles_range.is_some_and(|r| !r.contains((e.x, e.y)))
+                || (options.changed_only && !e.is_changed())
+            {
+                continue;
+            }
+            let state = match (e.reference, e.has_data()) {
+                (Some(_), _) => State::Reference,
+                (None, true) => State::Data,
+                (None, false) => State::Unchanged,
+            };
+            let reference = match e.reference {
+                Some(_) => Some(diff_file.referenced_name(&e)?.clone()),
+                None => None,
+            };
+            let changes = match (changes, e.stats) {
+                (false, _) => None,
+                (true, Some(s)) => Some(s.pixels),
+                // no statistics in v4; count them from the diff data
+                (true, None) if state == State::Data => {
+                    let mut payload = Vec::new();
+                    diff_file.open_chunk(&e)?.read_to_end(&mut payload)?;
+                    zstd_decompress(Cursor::new(&payload), &mut buf)?;
+                    Some(ChangeStats::from_diff_data(&buf).pixels)
+                }
+                (true, None) if state == State::Unchanged => Some(0),
+                (true, None) => None,
+            };
+            rows.push(Row {
+                x: e.x,
+                y: e.y,
+                state,
+                reference,
+                checksum: e.checksum,
+                size: e.len,
+                changes,
+            });
+        }
+
+        match options.sort {
+            // index entries are stored in (x, y) order
+            LsSort::Chunk => {}
+            LsSort::Size => rows.sort_by_key(|x| x.size),
+            LsSort::Changes => rows.sort_by_key(|x| x.changes),
+        }
+        if options.reverse {
+            rows.reverse();
+        }
+
+        if options.json {
+            println!("{}", serde_json::to_string(&rows)?);
+            return Ok(());
+        }
+        let mut out = BufWriter::new(stdout().lock());
+        let result: io::Result<()> = try {
+            for r in &rows {
+                let state = match (r.state, &r.reference) {
+                    (State::Reference, Some(name)) => format!("ref:{name}"),
+                    (State::Data, _) => "data".into(),
+                    _ => "unchanged".into(),
+                };
+                write!(
+                    out,
+                    "{}-{}\t{state}\t{:08x}\t{}",
+                    r.x, r.y, r.checksum, r.size
+                )?;
+                if let Some(c) = r.changes {
+                    write!(out, "\t{c}")?;
+                }
+                writeln!(out)?;
+            }
+            out.flush()?;
+        };
+        match result {
+            // e.g. piped to `head`
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            r => Ok(r?),
+        }
+    }
+}
+
+mod catalog {
+    use crate::thin::parse_duration;
+    use anyhow::anyhow;
+    use log::info;
+    use rayon::prelude::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use tempfile::NamedTempFile;
+    use wplace_tools::checksum::ChecksumAlgorithm;
+    use wplace_tools::diff::DiffFile;
     use wplace_tools::{
-        apply_chunk, chunk_buf, diff, new_chunk_file, open_file_range,
-        stylized_progress_bar, validate_chunk_checksum, zstd_compress_to, zstd_decompress, AnyhowErrorExt, ChunkFetcher,
-        ChunkNumber, ChunkProcessError, DirChunkFetcher, ExitOnError,
-        TarChunkFetcher, CHUNK_NUMBER_TOTAL,
+        DiffFilesCollector, Iso8601Name, name_timestamp, open_diff_source, parent_dir,
+        stylized_progress_bar,
     };
     use yeet_ops::yeet;
 
-    const ARRAY_LEN: usize = CHUNK_NUMBER_TOTAL * CHUNK_NUMBER_TOTAL;
-    const ZSTD_LEVEL: i32 = 3;
+    const MANIFEST_VERSION: u32 = 1;
 
-    #[inline(always)]
-    /// A simple transform from ChunkNumber(u16, u16) to the usize array index.
-    const fn array_index(n: ChunkNumber) -> usize {
-        (n.0 as usize * CHUNK_NUMBER_TOTAL) + n.1 as usize
+    #[derive(Serialize, Deserialize)]
+    struct Manifest {
+        version: u32,
+        diffs: Vec<Entry>,
     }
 
-    type DynFetcher = dyn ChunkFetcher + Send + Sync + 'static;
-    static LAST_INDEX: Lazy<Mutex<HashSet<ChunkNumber>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+    struct Entry {
+        name: Iso8601Name,
+        /// Previous snapshot in the chain; none for the first diff
+        parent: Option<Iso8601Name>,
+        size: u64,
+        sha256: String,
+        /// Diff format version
+        format_version: u16,
+        entries: u32,
+        changed: u32,
+        references: u32,
+    }
 
-    fn apply_1st_diff(
-        memory: Option<&mut [Option<Vec<u8>>]>,
-        base_fetcher: &DynFetcher,
-        diff: impl AsRef<Path>,
-        output: Option<&Path>,
-        no_checksum: bool,
-    ) -> anyhow::Result<()> {
-        let diff = diff.as_ref();
+    /// Default manifest path: `manifest.json` in a diff directory, or next to a SquashFS image.
+    pub fn default_manifest_path(source: &Path) -> PathBuf {
+        if source.is_dir() {
+            return source.join("manifest.json");
+        }
+        let mut name = source.file_name().unwrap_or_default().to_os_string();
+        name.push(".manifest.json");
+        parent_dir(source).join(name)
+    }
 
-        let mut diff_file = diff::DiffFile::open(File::open_buffered(diff)?)?;
-        let index = diff_file.collect_index()?;
-        let changed_chunks = index
-            .iter()
-            .filter(|x| x.1.is_changed())
-            .collect::<Vec<_>>();
-        let unchanged_chunks = index
+    fn describe(
+        source: &(dyn DiffFilesCollector + Sync),
+        name: &str,
+        parent: Option<&Iso8601Name>,
+    ) -> anyhow::Result<Entry> {
+        let mut reader = source.reader(name)?;
+        let mut hasher = ChecksumAlgorithm::Sha256.hasher();
+        let mut buf = vec![0_u8; 1024 * 1024];
+        let mut size = 0_u64;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        let sha256 = hasher
+            .finalize()
             .iter()
-            .filter(|x| !x.1.is_changed())
+            .map(|x| format!("{x:02x}"))
+            .collect();
+
+        reader.seek(SeekFrom::Start(0))?;
+        let mut diff_file = DiffFile::open(reader)?;
+        let index = diff_file.read_index()?;
+        Ok(Entry {
+            name: name.into(),
+            parent: parent.cloned(),
+            size,
+            sha256,
+            format_version: diff_file.version,
+            entries: diff_file.entry_count,
+            changed: index.iter().filter(|x| x.is_changed()).count() as u32,
+            references: index.iter().filter(|x| x.reference.is_some()).count() as u32,
+        })
+    }
+
+    fn collect(
+        source: &(dyn DiffFilesCollector + Sync),
+    ) -> Vec<(Iso8601Name, anyhow::Result<Entry>)> {
+        let names = source.name_iter().cloned().collect::<Vec<_>>();
+        let pb = stylized_progress_bar(names.len() as u64);
+        let entries = names
+            .par_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let parent = i.checked_sub(1).map(|p| &names[p]);
+                let entry = describe(source, name, parent);
+                pb.inc(1);
+                (name.clone(), entry)
+            })
             .collect::<Vec<_>>();
+        pb.finish();
+        entries
+    }
 
-        *LAST_INDEX.lock().unwrap() = index.iter().map(|x| *x.0).collect();
+    pub fn write(source_path: &Path, manifest_path: &Path) -> anyhow::Result<()> {
+        let source = open_diff_source(&[source_path])?;
+        info!("Hashing {} diff files...", source.name_iter().len());
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            diffs: collect(&*source)
+                .into_iter()
+                .map(|(name, e)| e.map_err(|e| anyhow!("Failed to read diff {name}: {e}")))
+                .collect::<anyhow::Result<_>>()?,
+        };
+        let temp_file = NamedTempFile::new_in(parent_dir(manifest_path))?;
+        let mut writer = File::create_buffered(temp_file.as_ref())?;
+        serde_json::to_writer_pretty(&mut writer, &manifest)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        drop(writer);
+        temp_file.persist(manifest_path)?;
+        info!(
+            "Manifest of {} diffs written to {}",
+            manifest.diffs.len(),
+            manifest_path.display()
+        );
+        Ok(())
+    }
 
-        let memory_ptr = memory.map(|x| x.as_mut_ptr() as usize);
+    /// Compare a diff source against its manifest. Returns whether they match.
+    ///
+    /// `max_gap`: report consecutive snapshots further apart than this
+    pub fn check(
+        source_path: &Path,
+        manifest_path: &Path,
+        max_gap: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let max_gap = max_gap.map(parse_duration).transpose()?;
+        let manifest: Manifest = serde_json::from_reader(File::open_buffered(manifest_path)?)?;
+        if manifest.version > MANIFEST_VERSION {
+            yeet!(anyhow!(
+                "Unsupported manifest version: {}",
+                manifest.version
+            ));
+        }
+        let source = open_diff_source(&[source_path])?;
+        info!("Hashing {} diff files...", source.name_iter().len());
+        let mut problems = Vec::new();
+        let mut actual = BTreeMap::new();
+        for (name, entry) in collect(&*source) {
+            match entry {
+                Ok(e) => {
+                    actual.insert(name, Some(e));
+                }
+                Err(e) => {
+                    problems.push(format!("{name}: unreadable ({e})"));
+                    actual.insert(name, None);
+                }
+            }
+        }
+        let expected = manifest
+            .diffs
+            .iter()
+            .map(|x| (x.name.clone(), x))
+            .collect::<BTreeMap<_, _>>();
+
+        for (name, e) in &expected {
+            match actual.get(name) {
+                None => problems.push(format!("{name}: missing")),
+                Some(Some(a)) if a.size != e.size || a.sha256 != e.sha256 => {
+                    problems.push(format!(
+                        "{name}: altered (size {} vs {}, SHA-256 {} vs {})",
+                        a.size, e.size, a.sha256, e.sha256
+                    ))
+                }
+                Some(_) => {}
+            }
+            // the chain breaks where a parent is absent
+            if let Some(parent) = &e.parent
+                && !actual.contains_key(parent)
+                && actual.contains_key(name)
+            {
+                problems.push(format!("{name}: gap, parent {parent} is absent"));
+            }
+        }
+        for name in actual.keys().filter(|x| !expected.contains_key(*x)) {
+            problems.push(format!("{name}: extra, not in the manifest"));
+        }
+        if let Some(max_gap) = max_gap {
+            let names = actual.keys().collect::<Vec<_>>();
+            for w in names.windows(2) {
+                let (Some(t0), Some(t1)) = (name_timestamp(w[0]), name_timestamp(w[1])) else {
+                    continue;
+                };
+                if t1 - t0 > max_gap {
+                    problems.push(format!(
+                        "{}: gap of {}s after {}",
+                        w[1],
+                        (t1 - t0) / 1000,
+                        w[0]
+                    ));
+                }
+            }
+        }
 
-        macro cell_ptr($n:expr, $ptr:expr) {
-            unsafe { &mut *($ptr as *mut Option<Vec<u8>>).add(array_index($n)) }
+        for p in &problems {
+            println!("{p}");
         }
+        match problems.is_empty() {
+            true => println!("{} diffs match the manifest.", actual.len()),
+            false => println!("{} problem(s) found.", problems.len()),
+        }
+        Ok(problems.is_empty())
+    }
+}
 
-        let pb = stylized_progress_bar(changed_chunks.len() as u64);
-        changed_chunks.into_iter().par_bridge().for_each_with(
-            (chunk_buf!(), chunk_buf!()),
-            |(raw_diff, base_buf), x| {
-                let result: anyhow::Result<()> = try {
-                    let &n = x.0;
-                    let entry = x.1;
+mod compare_chains {
+    use log::info;
+    use std::collections::{BTreeSet, HashMap};
+    use std::path::PathBuf;
+    use wplace_tools::diff::{DiffFile, IndexEntry};
+    use wplace_tools::{ChunkNumber, DiffFilesCollector, open_diff_source, stylized_progress_bar};
 
-                    let diff_reader = open_file_range(diff, entry.pos, entry.len)?;
-                    zstd_decompress(diff_reader, raw_diff)?;
+    /// Max chunks printed for each kind of difference in a diff
+    const MAX_PRINTED_CHUNKS: usize = 10;
 
-                    // if the base chunk is not present, the buffer will be just zeros
-                    // - totally fine for the applying process.
-                    let base_present = base_fetcher.fetch(n, base_buf)?;
-                    if !base_present {
-                        base_buf.fill(0);
-                    }
+    fn format_chunks<'a>(chunks: impl IntoIterator<Item = &'a ChunkNumber>) -> String {
+        let chunks = chunks.into_iter().collect::<Vec<_>>();
+        let mut s = chunks
+            .iter()
+            .take(MAX_PRINTED_CHUNKS)
+            .map(|(x, y)| format!("{x}-{y}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if chunks.len() > MAX_PRINTED_CHUNKS {
+            s.push_str(&format!(", ... ({} total)", chunks.len()));
+        }
+        s
+    }
 
-                    apply_chunk(
-                        base_buf,
-                        <&[_; _]>::try_from(&raw_diff[..])
-                            .expect("Raw diff data length is expected to be 1_000_000"),
-                    );
+    fn read_index(
+        source: &dyn DiffFilesCollector,
+        name: &str,
+    ) -> anyhow::Result<HashMap<ChunkNumber, IndexEntry>> {
+        DiffFile::open(source.reader(name)?)?.collect_index()
+    }
 
-                    if !no_checksum {
-                        validate_chunk_checksum(base_buf, entry.checksum)?;
-                    }
-                    if let Some(p) = memory_ptr {
-                        let cell_ptr = cell_ptr!(n, p);
-                        *cell_ptr = Some(zstd::encode_all(&base_buf[..], ZSTD_LEVEL)?);
-                    }
-                    if let Some(output) = output {
-                        let chunk_png = new_chunk_file(output, n, "png");
-                        write_chunk_png(chunk_png, base_buf)?;
-                    }
+    /// Compare two diff chains by the chunk checksums of their index entries. Returns whether
+    /// they match.
+    ///
+    /// Checksums are of the reconstructed chunks, so chains with different encodings (e.g.
+    /// references) still compare equal.
+    pub fn main(left: &[PathBuf], right: &[PathBuf]) -> anyhow::Result<bool> {
+        info!("Collecting diff files...");
+        let left = open_diff_source(left)?;
+        let right = open_diff_source(right)?;
+
+        let mut matched = true;
+        for name in left.name_iter().filter(|x| !right.contains(x)) {
+            println!("{name}: only in left");
+            matched = false;
+        }
+        for name in right.name_iter().filter(|x| !left.contains(x)) {
+            println!("{name}: only in right");
+            matched = false;
+        }
 
-                    pb.inc(1);
+        let common = left
+            .name_iter()
+            .filter(|x| right.contains(x))
+            .cloned()
+            .collect::<Vec<_>>();
+        // chunks with different checksums at the previous common snapshot
+        let mut diverged = BTreeSet::new();
+        let mut first_divergence = None;
+        let pb = stylized_progress_bar(common.len() as u64);
+        for name in &common {
+            let l = read_index(&*left, name)?;
+            let r = read_index(&*right, name)?;
+            let mut only_left = l.keys().filter(|x| !r.contains_key(x)).collect::<Vec<_>>();
+            let mut only_right = r.keys().filter(|x| !l.contains_key(x)).collect::<Vec<_>>();
+            only_left.sort_unstable();
+            only_right.sort_unstable();
+
+            let mut differ = BTreeSet::new();
+            let mut new = Vec::new();
+            for (n, le) in &l {
+                let Some(re) = r.get(n) else {
+                    continue;
                 };
+                if le.checksum == re.checksum {
+                    continue;
+                }
+                differ.insert(*n);
+                // unchanged in both chains since the divergence: carried over
+                if !(diverged.contains(n) && !le.is_changed() && !re.is_changed()) {
+                    new.push(*n);
+                }
+            }
+            new.sort_unstable();
+            let resolved = diverged.difference(&differ).collect::<Vec<_>>();
 
-                result.exit_with_chunk_context(*x.0, Some(diff));
-            },
-        );
-        pb.finish();
-
-        info!("Processing unchanged chunks...");
-        let pb = stylized_progress_bar(unchanged_chunks.len() as u64);
-        unchanged_chunks.into_iter().par_bridge().for_each_with(
-            chunk_buf!(),
-            |buf, (&n, entry)| {
-                let result: anyhow::Result<()> = try {
-                    let png_raw = base_fetcher.fetch_raw(n)?;
-
-                    if !no_checksum {
-                        read_png_reader(Cursor::new(&png_raw), buf)?;
-                        validate_chunk_checksum(buf, entry.checksum)?;
+            let diverges = !only_left.is_empty() || !only_right.is_empty() || !new.is_empty();
+            if diverges {
+                first_divergence.get_or_insert_with(|| name.clone());
+            }
+            if diverges || !resolved.is_empty() {
+                pb.suspend(|| {
+                    println!(
+                        "{name}: {} chunks differ ({} new), {} only in left, {} only in right",
+                        differ.len(),
+                        new.len(),
+                        only_left.len(),
+                        only_right.len()
+                    );
+                    if !new.is_empty() {
+                        println!("  new: {}", format_chunks(&new));
                     }
-                    if let Some(p) = memory_ptr {
-                        let cell_ptr = cell_ptr!(n, p);
-                        *cell_ptr = Some(zstd::encode_all(&buf[..], ZSTD_LEVEL)?);
+                    if !only_left.is_empty() {
+                        println!(
+                            "  only in left: {}",
+                            format_chunks(only_left.iter().copied())
+                        );
                     }
-                    if let Some(output) = output {
-                        let chunk_png = new_chunk_file(output, n, "png");
-                        io::copy(&mut (&png_raw[..]), &mut File::create_buffered(chunk_png)?)?;
+                    if !only_right.is_empty() {
+                        println!(
+                            "  only in right: {}",
+                            format_chunks(only_right.iter().copied())
+                        );
                     }
-
-                    pb.inc(1);
-                };
-                result.exit_with_chunk_context(n, Some(diff));
-            },
-        );
+                    if !resolved.is_empty() {
+                        println!("  equal again: {}", format_chunks(resolved.iter().copied()));
+                    }
+                });
+            }
+            diverged = differ;
+            pb.inc(1);
+        }
         pb.finish();
 
-        Ok(())
+        match &first_divergence {
+            None if matched => println!("Chains match at {} snapshots.", common.len()),
+            None => println!(
+                "Chunk states match at {} common snapshots; snapshot lists differ.",
+                common.len()
+            ),
+            Some(name) => println!(
+                "Chains diverge; first at {name} ({} common snapshots compared).",
+                common.len()
+            ),
+        }
+        Ok(matched && first_divergence.is_none())
     }
+}
 
-    fn apply_non_1st_diff(
-        memory: &mut [Option<Vec<u8>>],
-        diff: impl AsRef<Path>,
-        output: Option<&Path>,
-        no_checksum: bool,
-    ) -> anyhow::Result<()> {
-        let diff_path = diff.as_ref();
+mod shard {
+    use anyhow::anyhow;
+    use log::info;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{Read, Seek};
+    use std::path::{Path, PathBuf};
+    use tempfile::NamedTempFile;
+    use wplace_tools::diff::{DiffFile, DiffFileWriter, IndexEntry, Metadata, Shard};
+    use wplace_tools::{diff, parent_dir, stylized_progress_bar};
+    use yeet_ops::yeet;
 
-        let mut diff_file = DiffFile::open(File::open(diff_path)?)?;
-        let index = diff_file.collect_index()?;
-        let changed_entries: Vec<(ChunkNumber, IndexEntry)> = index
+    pub fn merge(paths: &[PathBuf], output: &Path) -> anyhow::Result<()> {
+        let mut files = Vec::new();
+        for path in paths {
+            let diff_file = DiffFile::open(File::open_buffered(path)?)?;
+            let Some(shard) = diff_file.metadata.shard.clone() else {
+                yeet!(anyhow!("{} is not a shard", path.display()));
+            };
+            files.push((shard, diff_file));
+        }
+        files.sort_by_key(|x| x.0.index);
+        let (first, first_file) = &files[0];
+        for (shard, diff_file) in &files[1..] {
+            if shard.diff != first.diff {
+                yeet!(anyhow!(
+                    "Shards of different diffs: {first} of {}, {shard} of {}",
+                    first.diff,
+                    shard.diff
+                ));
+            }
+            let (metadata, first_metadata) = (&diff_file.metadata, &first_file.metadata);
+            if metadata.checksum_algorithm != first_metadata.checksum_algorithm {
+                yeet!(anyhow!(
+                    "Shards {first} and {shard} use different checksum algorithms"
+                ));
+            }
+            if metadata.references != first_metadata.references {
+                yeet!(anyhow!(
+                    "Shards {first} and {shard} have different references"
+                ));
+            }
+            if metadata.region != first_metadata.region {
+                yeet!(anyhow!(
+                    "Shards {first} and {shard} are filtered to different regions"
+                ));
+            }
+        }
+        let count = first.count;
+        let indices = files
             .iter()
-            .filter(|(_, e)| e.is_changed())
-            .map(|x| (*x.0, *x.1))
-            .collect();
-
-        let memory_ptr = memory.as_mut_ptr() as usize;
+            .filter(|x| x.0.count == count)
+            .map(|x| x.0.index)
+            .collect::<BTreeSet<_>>();
+        if files.len() != count as usize || indices.len() != count as usize {
+            let missing = (0..count).filter(|x| !indices.contains(x)).count();
+            let listed = (0..count)
+                .filter(|x| !indices.contains(x))
+                .take(10)
+                .map(|x| Shard::new(x, count).unwrap().to_string())
+                .collect::<Vec<_>>();
+            yeet!(anyhow!(
+                "Expected shards 0..{count} of the same diff, each exactly once; {missing} missing: [{}{}]",
+                listed.join(", "),
+                if missing > listed.len() { ", ..." } else { "" }
+            ));
+        }
 
-        let get_cell = |n: ChunkNumber| unsafe {
-            let ptr = memory_ptr as *mut Option<Vec<u8>>;
-            &mut *ptr.add(array_index(n))
+        let references = files[0].1.metadata.references.clone();
+        let metadata = Metadata {
+            references: references.clone(),
+            checksum_algorithm: files[0].1.metadata.checksum_algorithm,
+            shard: None,
+            region: files[0].1.metadata.region.clone(),
         };
-        let decompress_to = |n: ChunkNumber, to: &mut [u8]| {
-            // SAFETY: Rayon ensures unique access to each entry during iteration,
-            // and we are accessing index 'idx' which is unique to ChunkNumber 'n'
-            let cell_ptr = get_cell(n);
-
-            // Decompress existing base
-            if let Some(compressed_base) = cell_ptr.as_ref() {
-                zstd_decompress(Cursor::new(compressed_base), to).unwrap();
-            } else {
-                to.fill(0);
+        let temp_file = NamedTempFile::new_in(parent_dir(output))?;
+        let mut writer = DiffFileWriter::create(
+            File::create_buffered(temp_file.as_ref())?,
+            metadata,
+            diff::VERSION,
+        )?;
+        // Shards cover ascending column ranges, so their entries are concatenated in order.
+        let progress = stylized_progress_bar(files.len() as u64);
+        for (shard, diff_file) in &mut files {
+            for e in diff_file.read_index()? {
+                if !shard.contains((e.x, e.y)) {
+                    yeet!(anyhow!(
+                        "Chunk {:?} is out of the range of shard {shard}",
+                        (e.x, e.y)
+                    ));
+                }
+                let reference = map_reference(diff_file, &e, &references)?;
+                writer.copy_entry(diff_file, &e, reference)?;
             }
-            cell_ptr
+            progress.inc(1);
+        }
+        progress.finish();
+        writer.finalize()?;
+        temp_file.persist(output)?;
+        info!("Merged {count} shards into {}", output.display());
+        Ok(())
+    }
+
+    pub fn split(path: &Path, count: u16, output_dir: &Path) -> anyhow::Result<()> {
+        let mut diff_file = DiffFile::open(File::open_buffered(path)?)?;
+        if let Some(shard) = &diff_file.metadata.shard {
+            yeet!(anyhow!("The diff is already shard {shard}"));
+        }
+        let stem = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| anyhow!("Invalid file name"))?;
+        fs::create_dir_all(output_dir)?;
+        // v4 files have no digest
+        let identity = match diff_file.version {
+            4 => stem.to_string(),
+            _ => diff_file
+                .read_digest()?
+                .iter()
+                .map(|x| format!("{x:02x}"))
+                .collect(),
         };
 
-        let delete_chunk_count: usize = {
-            let mut last_index = LAST_INDEX.lock().unwrap();
-            let set = index.iter().map(|x| *x.0).collect::<HashSet<_>>();
-            let deleted_chunks = last_index.difference(&set);
-
-            // Please see [comment 1] in retrieve.rs.
-            let mut deleted_counter = 0_usize;
-            for &n in deleted_chunks {
-                let cell_ptr = get_cell(n);
-                if let Some(x) = cell_ptr {
-                    x.clear();
-                    zstd_compress_to(x, ZSTD_LEVEL, &chunk_buf!())?;
-                }
-                deleted_counter += 1;
+        let references = diff_file.metadata.references.clone();
+        let entries = diff_file.read_index()?;
+        let progress = stylized_progress_bar(count as u64);
+        for index in 0..count {
+            let shard = Shard::new(index, count)?.of_diff(&identity);
+            let output = output_dir.join(format!("{stem}.shard-{index}-of-{count}.diff"));
+            let temp_file = NamedTempFile::new_in(output_dir)?;
+            let metadata = Metadata {
+                references: references.clone(),
+                checksum_algorithm: diff_file.metadata.checksum_algorithm,
+                shard: Some(shard.clone()),
+                region: diff_file.metadata.region.clone(),
+            };
+            let mut writer = DiffFileWriter::create(
+                File::create_buffered(temp_file.as_ref())?,
+                metadata,
+                diff::VERSION,
+            )?;
+            for e in entries.iter().filter(|e| shard.contains((e.x, e.y))) {
+                let reference = map_reference(&diff_file, e, &references)?;
+                writer.copy_entry(&mut diff_file, e, reference)?;
             }
+            writer.finalize()?;
+            temp_file.persist(output)?;
+            progress.inc(1);
+        }
+        progress.finish();
+        info!("Split into {count} shards in {}", output_dir.display());
+        Ok(())
+    }
 
-            // update the last index
-            last_index.clear();
-            for n in set {
-                last_index.insert(n);
-            }
+    /// Index of the diff referenced by `e` in `references`
+    pub fn map_reference<R: Read + Seek>(
+        diff_file: &DiffFile<R>,
+        e: &IndexEntry,
+        references: &[String],
+    ) -> anyhow::Result<Option<u16>> {
+        if e.reference.is_none() {
+            return Ok(None);
+        }
+        let name = diff_file.referenced_name(e)?;
+        let r = references.iter().position(|x| x == name).unwrap();
+        Ok(Some(r as u16))
+    }
+}
 
-            deleted_counter
-        };
+mod export_region {
+    use crate::{filter_diff, write_snapshot_tar};
+    use anyhow::anyhow;
+    use log::info;
+    use rayon::prelude::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use wplace_tools::diff::DiffFile;
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::tar::is_tarball;
+    use wplace_tools::{
+        ChunkFetcher, ChunkSelection, TarChunkFetcher, open_chunk_fetcher, open_diff_source,
+        stylized_progress_bar,
+    };
+    use yeet_ops::yeet;
+
+    /// Write the selected region of a chain to `output`: the regional base tarball
+    /// `<root name>.tar`, and the filtered diffs in `diffs/`.
+    pub fn main(
+        base: &Path,
+        diff_source: &[PathBuf],
+        selection: &ChunkSelection,
+        output: &Path,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<()> {
+        info!("Collecting diff files...");
+        let source = open_diff_source(diff_source)?;
+        let diffs_dir = output.join("diffs");
+        if diffs_dir.exists() {
+            yeet!(anyhow!("Output exists: {}", diffs_dir.display()));
+        }
+
+        info!("Reading base snapshot...");
+        let (base_fetcher, root_name): (Arc<dyn ChunkFetcher + Send + Sync>, _) =
+            match is_tarball(base) {
+                true => {
+                    let fetcher = TarChunkFetcher::new(base, layout)?;
+                    let root_name = fetcher.root_name().to_string();
+                    (Arc::new(fetcher), root_name)
+                }
+                false => (
+                    open_chunk_fetcher(base, true, layout)?,
+                    base.file_name()
+                        .and_then(|x| x.to_str())
+                        .ok_or_else(|| anyhow!("Invalid base snapshot name"))?
+                        .to_string(),
+                ),
+            };
+        fs::create_dir_all(&diffs_dir)?;
+        let base_output = output.join(format!("{root_name}.tar"));
         info!(
-            "(deleted: {}, changed + added: {})",
-            delete_chunk_count,
-            changed_entries.len()
+            "Writing regional base snapshot to {}...",
+            base_output.display()
         );
+        write_snapshot_tar(&*base_fetcher, &root_name, &base_output, selection)?;
+
+        let names = source.name_iter().cloned().collect::<Vec<_>>();
+        info!("Filtering {} diffs...", names.len());
+        let pb = stylized_progress_bar(names.len() as u64);
+        let kept = names
+            .par_iter()
+            .map(|name| {
+                let diff_file = DiffFile::open(source.reader(name)?)?;
+                let (kept, ..) = filter_diff(
+                    diff_file,
+                    &diffs_dir.join(format!("{name}.diff")),
+                    selection,
+                )
+                .map_err(|e| anyhow!("Failed to filter diff {name}: {e}"))?;
+                pb.inc(1);
+                Ok(kept)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        pb.finish();
 
-        let pb = stylized_progress_bar(changed_entries.len() as u64);
-        changed_entries.into_iter().par_bridge().for_each_with(
-            (chunk_buf!(), chunk_buf!()),
-            |(base_buf, diff_data_buf), (n, entry)| {
-                let result: anyhow::Result<()> = try {
-                    let cell_ptr = decompress_to(n, base_buf);
+        let size = fs::read_dir(&diffs_dir)?
+            .filter_map(|x| x.ok()?.metadata().ok())
+            .map(|x| x.len())
+            .sum::<u64>()
+            + fs::metadata(&base_output)?.len();
+        info!(
+            "Exported {} diffs with up to {} chunks each; {size} bytes in total. Use with: retrieve -b {} -d {}",
+            names.len(),
+            kept.iter().max().copied().unwrap_or_default(),
+            base_output.display(),
+            diffs_dir.display()
+        );
+        Ok(())
+    }
+}
 
-                    // Load and apply diff
-                    let diff_reader = open_file_range(diff_path, entry.pos, entry.len)?;
-                    zstd_decompress(diff_reader, diff_data_buf)?;
+mod compare {
+    use anyhow::anyhow;
+    use log::info;
+    use png::{BitDepth, ColorType, Encoder};
+    use rayon::prelude::*;
+    use serde::Serialize;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::fs::File;
+    use std::path::Path;
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{
+        CHUNK_DIMENSION, CHUNK_LENGTH, ChunkNumber, GLOBAL_PALETTE, PALETTE_INDEX_MASK, chunk_buf,
+        open_chunk_fetcher, stylized_progress_bar,
+    };
 
-                    apply_chunk(base_buf, (&diff_data_buf[..]).try_into().unwrap());
-                    if !no_checksum {
-                        validate_chunk_checksum(base_buf, entry.checksum)?;
-                    }
+    #[derive(Serialize)]
+    struct DifferingChunk {
+        x: u16,
+        y: u16,
+        /// Number of pixels with different colors
+        pixels: u32,
+    }
 
-                    // Recompress and update memory
-                    if let Some(buf) = &mut *cell_ptr {
-                        buf.clear();
-                        zstd_compress_to(buf, ZSTD_LEVEL, base_buf)?;
-                    } else {
-                        let re_compressed = zstd::encode_all(&base_buf[..], ZSTD_LEVEL)?;
-                        *cell_ptr = Some(re_compressed);
-                    }
-                    pb.inc(1);
-                };
-                result.exit_with_chunk_context(n, Some(diff_path));
-            },
-        );
-        pb.finish();
+    #[derive(Serialize)]
+    struct Report {
+        /// Number of chunks present in both
+        common: usize,
+        /// Chunks only in `new`
+        added: Vec<ChunkNumber>,
+        /// Chunks only in `base`
+        removed: Vec<ChunkNumber>,
+        differing: Vec<DifferingChunk>,
+    }
 
-        if let Some(output) = output {
-            info!("Writing to disk...");
-            let pb = stylized_progress_bar(index.len() as u64);
-            index
-                .into_iter()
-                .par_bridge()
-                .for_each_with(chunk_buf!(), |buf, (n, entry)| {
-                    let result: anyhow::Result<()> = try {
-                        let _cell_ptr = decompress_to(n, buf);
-                        write_chunk_png(new_chunk_file(output, n, "png"), buf)?;
-                        if !no_checksum {
-                            validate_chunk_checksum(buf, entry.checksum)?;
+    /// Compare two snapshots chunk by chunk. Returns whether they're identical.
+    ///
+    /// If `visual_diff` is present, an image of each differing chunk is written to
+    /// `<visual_diff>/<x>-<y>.png`.
+    pub fn main(
+        base: &Path,
+        new: &Path,
+        json: bool,
+        visual_diff: Option<&Path>,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<bool> {
+        info!("Reading 'base'...");
+        let base_fetcher = open_chunk_fetcher(base, true, layout)?;
+        info!("Reading 'new'...");
+        let new_fetcher = open_chunk_fetcher(new, true, layout)?;
+        let base_chunks = base_fetcher.chunks_iter().collect::<BTreeSet<_>>();
+        let new_chunks = new_fetcher.chunks_iter().collect::<BTreeSet<_>>();
+        let common = base_chunks
+            .intersection(&new_chunks)
+            .copied()
+            .collect::<Vec<_>>();
+        if let Some(dir) = visual_diff {
+            fs::create_dir_all(dir)?;
+        }
+
+        info!("Processing {} files...", common.len());
+        let progress = stylized_progress_bar(common.len() as u64);
+        let differing = common
+            .par_iter()
+            .map_init(
+                || (chunk_buf!(), chunk_buf!()),
+                |(base_buf, new_buf), &n| {
+                    let result: anyhow::Result<_> = try {
+                        let present =
+                            base_fetcher.fetch(n, base_buf)? && new_fetcher.fetch(n, new_buf)?;
+                        if !present {
+                            Err(anyhow!("Failed to read chunk {n:?}"))?;
                         }
-                        pb.inc(1);
+                        progress.inc(1);
+                        if base_buf == new_buf {
+                            return Ok(None);
+                        }
+                        let pixels = base_buf
+                            .iter()
+                            .zip(new_buf.iter())
+                            .filter(|&(&a, &b)| a & PALETTE_INDEX_MASK != b & PALETTE_INDEX_MASK)
+                            .count() as u32;
+                        if let Some(dir) = visual_diff {
+                            write_visual_diff(
+                                &dir.join(format!("{}-{}.png", n.0, n.1)),
+                                base_buf,
+                                new_buf,
+                            )?;
+                        }
+                        Some(DifferingChunk {
+                            x: n.0,
+                            y: n.1,
+                            pixels,
+                        })
                     };
-                    result.exit_with_chunk_context(n, Some(diff_path));
-                });
-            pb.finish();
+                    result
+                },
+            )
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        progress.finish();
+
+        let report = Report {
+            common: common.len(),
+            added: new_chunks.difference(&base_chunks).copied().collect(),
+            removed: base_chunks.difference(&new_chunks).copied().collect(),
+            differing,
+        };
+        let identical =
+            report.added.is_empty() && report.removed.is_empty() && report.differing.is_empty();
+        if json {
+            println!("{}", serde_json::to_string(&report)?);
+            return Ok(identical);
+        }
+        for (x, y) in &report.added {
+            println!("{x}-{y}: added");
+        }
+        for (x, y) in &report.removed {
+            println!("{x}-{y}: removed");
         }
+        for c in &report.differing {
+            println!("{}-{}: {} pixels differ", c.x, c.y, c.pixels);
+        }
+        println!(
+            "{} common chunks, {} differ ({} pixels); {} added, {} removed.",
+            report.common,
+            report.differing.len(),
+            report
+                .differing
+                .iter()
+                .map(|x| x.pixels as u64)
+                .sum::<u64>(),
+            report.added.len(),
+            report.removed.len()
+        );
+        Ok(identical)
+    }
 
+    /// Write an RGBA image of the differences: changed pixels in their new color (magenta if
+    /// they became transparent), and the unchanged ones faded.
+    fn write_visual_diff(path: &Path, base: &[u8], new: &[u8]) -> anyhow::Result<()> {
+        let mut rgba = vec![0_u8; CHUNK_LENGTH * 4];
+        for ((pixel, &a), &b) in rgba.chunks_exact_mut(4).zip(base).zip(new) {
+            let (a, b) = (a & PALETTE_INDEX_MASK, b & PALETTE_INDEX_MASK);
+            let color = match (a == b, b) {
+                (true, 0) => [0, 0, 0, 0],
+                (true, _) => {
+                    let [r, g, b] = GLOBAL_PALETTE[b as usize];
+                    [r, g, b, 48]
+                }
+                (false, 0) => [255, 0, 255, 255],
+                (false, _) => {
+                    let [r, g, b] = GLOBAL_PALETTE[b as usize];
+                    [r, g, b, 255]
+                }
+            };
+            pixel.copy_from_slice(&color);
+        }
+        let mut encoder = Encoder::new(
+            File::create_buffered(path)?,
+            CHUNK_DIMENSION.0,
+            CHUNK_DIMENSION.1,
+        );
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&rgba)?;
+        writer.finish()?;
         Ok(())
     }
+}
+
+mod filter {
+    use crate::write_snapshot;
+    use log::info;
+    use std::path::Path;
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{ChunkSelection, open_chunk_fetcher};
+
+    /// Copy chunks of `base` selected by `selection` to `output`. See [`write_snapshot`] for
+    /// the output formats.
+    pub fn main(
+        base: &Path,
+        output: &Path,
+        selection: &ChunkSelection,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<()> {
+        info!("Collecting files...");
+        let fetcher = open_chunk_fetcher(base, true, layout)?;
+        info!("Writing to {}...", output.display());
+        write_snapshot(&*fetcher, output, selection)
+    }
+}
+
+mod apply {
+    use crate::cli::ApplyCmd;
+    use crate::write_snapshot;
+    use log::{info, warn};
+    use std::process::exit;
+    use std::sync::Arc;
+    use wplace_tools::chain::{ChainChunkFetcher, ChainReplay, open_chain_base};
+    use wplace_tools::layout::ChunkPathLayout;
+    use wplace_tools::{ChunkSelection, DiffFileListCollector, DiffFilesCollector};
 
-    pub fn main(mut args: ApplyCmd) -> anyhow::Result<()> {
+    pub fn main(mut args: ApplyCmd, layout: &ChunkPathLayout) -> anyhow::Result<()> {
         if !args.dry_run && args.output.is_none() {
             warn!(
                 "`--output` is missed? Please add `--dry-run` when you intend to ignore the output."
@@ -624,73 +3563,423 @@ mod apply {
         if args.dry_run {
             args.output = None;
         }
-
-        let base_fetcher: Box<DynFetcher> =
-            if args.initial.extension().map(|x| x.to_ascii_lowercase()) == Some("tar".into()) {
-                info!("Reading base tarball...");
-                Box::new(TarChunkFetcher::new(&args.initial)?)
-            } else if args.initial.is_dir() {
-                Box::new(DirChunkFetcher::new(&args.initial, false)?)
-            } else {
-                yeet!(anyhow::anyhow!("Unknown 'base' file type"))
-            };
         assert!(!args.diffs.is_empty(), "Clap ensures");
 
+        let source = Arc::new(DiffFileListCollector::new(&args.diffs)?);
+        let names = source.name_iter().cloned().collect::<Vec<_>>();
+        info!("Reading base snapshot...");
+        let base_fetcher = open_chain_base(&args.initial, &*source, &names, layout)?;
+        info!("Scanning references...");
+        let mut replay = ChainReplay::new(base_fetcher, source, names)?;
+        replay.validate = !args.no_checksum;
+        replay.validate_base = !args.no_checksum;
+
         let diff_total = args.diffs.len();
-        let print_log = |i: usize, path: &Path| {
+        for (i, path) in args.diffs.iter().enumerate() {
             info!(
                 "Applying diff [{}/{}]: {}...",
-                i,
+                i + 1,
                 diff_total,
                 path.display()
             );
-        };
+            let stats = replay.step()?;
+            info!(
+                "(deleted: {}, changed + added: {})",
+                stats.deleted,
+                stats.changed + stats.references
+            );
+        }
 
-        if args.diffs.len() == 1 {
-            print_log(1, &args.diffs[0]);
-            // There's only one diff to be applied. No need to write to an intermediate memory.
-            apply_1st_diff(
-                None,
-                &*base_fetcher,
-                &args.diffs[0],
-                args.output.as_deref(),
-                args.no_checksum,
+        if let Some(output) = &args.output {
+            info!("Writing to {}...", output.display());
+            write_snapshot(
+                &ChainChunkFetcher::new(replay),
+                output,
+                &ChunkSelection::default(),
             )?;
-            return Ok(());
         }
 
-        // Buffer to store all the (intermediate) processed data
-        let mut memory_store: Vec<Option<Vec<u8>>> = (0..ARRAY_LEN).map(|_| None).collect();
+        info!("Done.");
+        Ok(())
+    }
+}
 
-        let first = &args.diffs[0];
-        let last = &args.diffs[args.diffs.len() - 1];
-        let intermediates = &args.diffs[1..(args.diffs.len() - 1)];
-        print_log(1, first);
-        apply_1st_diff(
-            Some(&mut memory_store),
-            &*base_fetcher,
-            first,
-            None,
-            args.no_checksum,
-        )?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::ApplyCmd;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+    use wplace_tools::indexed_png::write_chunk_png_to;
+    use wplace_tools::tar::states_path;
+    use wplace_tools::{CHUNK_LENGTH, DirDiffFilesCollector};
+
+    const NAMES: [&str; 4] = [
+        "2025-08-09T20-01-14.231Z",
+        "2025-08-09T20-31-15.102Z",
+        "2025-08-09T21-01-13.876Z",
+        "2025-08-09T21-31-16.540Z",
+    ];
+
+    /// Chunk filled with palette index `color`, with a line of `color + 1` at row `row`
+    fn chunk(color: u8, row: usize) -> Vec<u8> {
+        let mut buf = vec![color; CHUNK_LENGTH];
+        buf[row * 1000..(row + 1) * 1000].fill(color + 1);
+        buf
+    }
 
-        for (diff_i, diff_path) in intermediates.iter().enumerate() {
-            print_log(diff_i + 1 + 1, diff_path);
-            apply_non_1st_diff(&mut memory_store, diff_path, None, args.no_checksum)?;
+    /// Chunk states of the snapshots `NAMES`. Chunks return to states of the second snapshot in
+    /// the last one, so its diff has reference entries.
+    fn snapshots() -> Vec<BTreeMap<ChunkNumber, Vec<u8>>> {
+        let (a0, a1, a2) = (chunk(1, 0), chunk(2, 10), chunk(3, 20));
+        let (b0, b1) = (chunk(4, 0), chunk(4, 30));
+        let (c0, d0, d1) = (chunk(5, 0), chunk(6, 0), chunk(6, 40));
+        vec![
+            BTreeMap::from([((10, 1), a0), ((10, 2), b0.clone()), ((11, 1), c0.clone())]),
+            BTreeMap::from([
+                ((10, 1), a1.clone()),
+                ((10, 2), b0.clone()),
+                ((11, 1), c0.clone()),
+                ((12, 3), d0.clone()),
+            ]),
+            BTreeMap::from([((10, 1), a2), ((10, 2), b1), ((12, 3), d0)]),
+            BTreeMap::from([((10, 1), a1), ((10, 2), b0), ((11, 1), c0), ((12, 3), d1)]),
+        ]
+    }
+
+    fn write_snapshot_dir(dir: &Path, chunks: &BTreeMap<ChunkNumber, Vec<u8>>) {
+        for (&n, data) in chunks {
+            let file = File::create_buffered(new_chunk_file(dir, n, "png")).unwrap();
+            write_chunk_png_to(file, data).unwrap();
         }
+    }
 
-        print_log(diff_total, last);
-        apply_non_1st_diff(
-            &mut memory_store,
-            last,
-            args.output.as_deref(),
-            args.no_checksum,
-        )?;
+    fn read_snapshot(fetcher: &dyn ChunkFetcher) -> BTreeMap<ChunkNumber, Vec<u8>> {
+        fetcher
+            .chunks_iter()
+            .map(|n| {
+                let mut buf = chunk_buf!();
+                assert!(fetcher.fetch(n, &mut buf).unwrap());
+                (n, buf)
+            })
+            .collect()
+    }
 
-        info!("Done.");
-        Ok(())
+    /// Snapshot directories of `snapshots()` and the diff chain between them in `diffs/`, made
+    /// like `diff --history --history-base --verify` does.
+    fn make_chain(root: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let snapshots = snapshots();
+        let dirs = NAMES.map(|x| root.join(x)).to_vec();
+        for (dir, chunks) in dirs.iter().zip(&snapshots) {
+            write_snapshot_dir(dir, chunks);
+        }
+        let diff_dir = root.join("diffs");
+        fs::create_dir(&diff_dir).unwrap();
+        let mut diffs = Vec::new();
+        for i in 1..NAMES.len() {
+            let (recent_states, base_states, history) = match i {
+                1 => (None, None, None),
+                _ => {
+                    let source =
+                        open_history(&dirs[i - 1], std::slice::from_ref(&diff_dir)).unwrap();
+                    let history = ChainHistory {
+                        base: Arc::new(DirChunkFetcher::new(&dirs[0], true).unwrap()),
+                        source: Arc::clone(&source),
+                    };
+                    (
+                        Some(RecentStates::collect(&*source, 100).unwrap()),
+                        Some(collect_base_states(&*source, &source.last()).unwrap()),
+                        Some(history),
+                    )
+                }
+            };
+            let options = DiffOptions {
+                recent_states,
+                base_states,
+                verify: true,
+                history,
+                ..Default::default()
+            };
+            let output = diff_dir.join(format!("{}.diff", NAMES[i]));
+            do_diff_for_directory(
+                dirs[i - 1].clone(),
+                dirs[i].clone(),
+                output.clone(),
+                options,
+            )
+            .unwrap();
+            diffs.push(output);
+        }
+        (dirs, diffs)
     }
-}
 
-#[test]
-fn test() {}
+    /// Diff of the first two snapshots of `snapshots()`, written to `root/<name>.diff`
+    fn make_diff(root: &Path, options: DiffOptions) -> (PathBuf, PathBuf) {
+        let snapshots = snapshots();
+        let dirs = NAMES[..2].iter().map(|x| root.join(x)).collect::<Vec<_>>();
+        for (dir, chunks) in dirs.iter().zip(&snapshots) {
+            write_snapshot_dir(dir, chunks);
+        }
+        let output = root.join(format!("{}.diff", NAMES[1]));
+        do_diff_for_directory(dirs[0].clone(), dirs[1].clone(), output.clone(), options).unwrap();
+        (dirs[0].clone(), output)
+    }
+
+    fn apply_cmd(initial: &Path, diffs: &[PathBuf], output: &Path) -> ApplyCmd {
+        ApplyCmd {
+            initial: initial.into(),
+            diffs: diffs.to_vec(),
+            output: Some(output.into()),
+            dry_run: false,
+            no_checksum: false,
+        }
+    }
+
+    #[test]
+    fn reference_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let (dirs, diffs) = make_chain(temp.path());
+        let snapshots = snapshots();
+
+        let mut last = diff::DiffFile::open_path(&diffs[2]).unwrap();
+        let references = last
+            .read_index()
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.reference.is_some())
+            .map(|e| ((e.x, e.y), last.referenced_name(&e).unwrap().clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            references,
+            [(10, 1), (10, 2), (11, 1)].map(|n| (n, NAMES[1].to_string()))
+        );
+
+        // apply
+        let output = temp.path().join("applied");
+        apply::main(apply_cmd(&dirs[0], &diffs, &output), &Default::default()).unwrap();
+        let applied = DirChunkFetcher::new(&output, true).unwrap();
+        assert_eq!(read_snapshot(&applied), snapshots[3]);
+
+        // a whole replay, and a replay of a few chunks like `retrieve` does
+        let source: Arc<dyn DiffFilesCollector + Send + Sync> =
+            Arc::new(DirDiffFilesCollector::new([temp.path().join("diffs")]).unwrap());
+        let base: Arc<dyn ChunkFetcher + Send + Sync> =
+            Arc::new(DirChunkFetcher::new(&dirs[0], true).unwrap());
+        let names = source.name_iter().cloned().collect::<Vec<_>>();
+        let mut replay =
+            ChainReplay::new(Arc::clone(&base), Arc::clone(&source), names.clone()).unwrap();
+        let chunks = [(10, 1), (11, 1)];
+        let mut subset = ChainReplay::for_chunks(base, source, names, chunks).unwrap();
+        for expected in &snapshots[1..] {
+            replay.step().unwrap();
+            subset.step().unwrap();
+            let replayed = read_snapshot(&ChainChunkFetcher::new(replay.clone()));
+            assert_eq!(&replayed, expected);
+            let mut buf = chunk_buf!();
+            for n in chunks {
+                assert_eq!(
+                    subset.fetch(n, &mut buf).unwrap(),
+                    expected.contains_key(&n)
+                );
+                if let Some(data) = expected.get(&n) {
+                    assert_eq!(&buf, data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn diff_is_reproducible() {
+        let temp = TempDir::new().unwrap();
+        let (base, first) = make_diff(temp.path(), DiffOptions::default());
+        let second = temp.path().join("second.diff");
+        let new = temp.path().join(NAMES[1]);
+        do_diff_for_directory(base, new, second.clone(), DiffOptions::default()).unwrap();
+        assert!(fs::read(first).unwrap() == fs::read(second).unwrap());
+    }
+
+    #[test]
+    fn diff_records_change_stats() {
+        let temp = TempDir::new().unwrap();
+        let (_, diff) = make_diff(temp.path(), DiffOptions::default());
+        let index = diff::DiffFile::open_path(&diff)
+            .unwrap()
+            .collect_index()
+            .unwrap();
+        let stats = |n| index[&n].stats.unwrap();
+        // all but the first row changed
+        assert_eq!(
+            stats((10, 1)),
+            ChangeStats {
+                pixels: 999_000,
+                bbox: (0, 1, 999, 999),
+            }
+        );
+        assert_eq!(stats((10, 2)), ChangeStats::default());
+        // new chunk
+        assert_eq!(
+            stats((12, 3)),
+            ChangeStats {
+                pixels: 1_000_000,
+                bbox: (0, 0, 999, 999),
+            }
+        );
+    }
+
+    #[test]
+    fn apply_refuses_shards() {
+        let temp = TempDir::new().unwrap();
+        let shard = Shard::new(0, 2).unwrap().of_diff("a..b");
+        let options = DiffOptions {
+            shard: Some(shard),
+            verify: true,
+            ..Default::default()
+        };
+        let (base, diff) = make_diff(temp.path(), options);
+        let output = temp.path().join("applied");
+        let e = apply::main(apply_cmd(&base, &[diff], &output), &Default::default()).unwrap_err();
+        assert!(e.to_string().contains("is only shard 0/2 of a diff"), "{e}");
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn apply_refuses_bases_outside_region() {
+        let temp = TempDir::new().unwrap();
+        let (base, diff) = make_diff(temp.path(), DiffOptions::default());
+        let selection = ChunkSelection {
+            chunks: Some([(10, 1), (10, 2)].into()),
+            ..Default::default()
+        };
+        let regional = temp.path().join("regional");
+        fs::create_dir(&regional).unwrap();
+        let regional_diff = regional.join(format!("{}.diff", NAMES[1]));
+        filter_diff(
+            diff::DiffFile::open_path(&diff).unwrap(),
+            &regional_diff,
+            &selection,
+        )
+        .unwrap();
+        let diffs = [regional_diff];
+
+        let output = temp.path().join("applied");
+        let e = apply::main(apply_cmd(&base, &diffs, &output), &Default::default()).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("The base snapshot has chunk (11, 1) outside the region"),
+            "{e}"
+        );
+        assert!(!output.exists());
+
+        // the regional base snapshot is accepted
+        let regional_base = regional.join(NAMES[0]);
+        filter::main(&base, &regional_base, &selection, &Default::default()).unwrap();
+        apply::main(
+            apply_cmd(&regional_base, &diffs, &output),
+            &Default::default(),
+        )
+        .unwrap();
+        let mut expected = snapshots().swap_remove(1);
+        expected.retain(|&n, _| selection.contains(n));
+        assert_eq!(
+            read_snapshot(&DirChunkFetcher::new(&output, true).unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn merge_refuses_mismatched_shards() {
+        let temp = TempDir::new().unwrap();
+        let shards = (0..2)
+            .map(|i| {
+                let root = temp.path().join(format!("shard-{i}"));
+                let options = DiffOptions {
+                    shard: Some(Shard::new(i, 2).unwrap().of_diff("a..b")),
+                    ..Default::default()
+                };
+                make_diff(&root, options).1
+            })
+            .collect::<Vec<_>>();
+        let output = temp.path().join("merged.diff");
+        shard::merge(&shards, &output).unwrap();
+        let mut merged = diff::DiffFile::open_path(&output).unwrap();
+        assert!(merged.metadata.shard.is_none());
+        assert_eq!(merged.read_index().unwrap().len(), snapshots()[1].len());
+
+        let selection = ChunkSelection {
+            chunks: Some([(10, 1)].into()),
+            ..Default::default()
+        };
+        let regional = temp.path().join("regional.diff");
+        filter_diff(
+            diff::DiffFile::open_path(&shards[1]).unwrap(),
+            &regional,
+            &selection,
+        )
+        .unwrap();
+        let e = shard::merge(&[shards[0].clone(), regional], &output).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Shards 0/2 and 1/2 are filtered to different regions"
+        );
+    }
+
+    fn write_tar(path: &Path, chunks: &BTreeMap<ChunkNumber, Vec<u8>>) {
+        let root_name = path.file_stem().unwrap().to_str().unwrap();
+        let file = File::create_buffered(path).unwrap();
+        let mut writer = ChunksTarWriter::new(file, root_name).unwrap();
+        for (&n, data) in chunks {
+            let mut png = Vec::new();
+            write_chunk_png_to(&mut png, data).unwrap();
+            writer.add_chunk(n, &png).unwrap();
+        }
+        writer.finish().unwrap().flush().unwrap();
+    }
+
+    #[test]
+    fn tar_diff_reuses_chunk_states() {
+        let temp = TempDir::new().unwrap();
+        let layout = ChunkPathLayout::default();
+        let tars = NAMES[..3]
+            .iter()
+            .map(|x| temp.path().join(format!("{x}.tar")))
+            .collect::<Vec<_>>();
+        for (tar, chunks) in tars.iter().zip(&snapshots()) {
+            write_tar(tar, chunks);
+        }
+        let diff = |i: usize, name: &str| {
+            let output = temp.path().join(name);
+            let (base, new) = (tars[i - 1].clone(), tars[i].clone());
+            do_diff_for_tar(base, new, output.clone(), DiffOptions::default(), &layout).unwrap();
+            diff::DiffFile::open_path(&output).unwrap()
+        };
+        let index_states = |mut diff_file: diff::DiffFile<_>| {
+            diff_file
+                .collect_index()
+                .unwrap()
+                .into_iter()
+                .map(|(n, e)| (n, (e.checksum, e.state_digest.unwrap())))
+                .collect::<ChunkStates>()
+        };
+
+        // states of the new tarball are saved as computed by decoding it
+        let first = diff(1, "first.diff");
+        let states = read_states_file(&tars[1], &layout).unwrap().unwrap();
+        assert_eq!(states, index_states(first));
+
+        // with them the diff matches the one decoding all chunks
+        let fast = index_states(diff(2, "fast.diff"));
+        fs::remove_file(states_path(&tars[1])).unwrap();
+        let slow = index_states(diff(2, "slow.diff"));
+        assert_eq!(fast, slow);
+        assert_eq!(
+            fs::read(temp.path().join("fast.diff")).unwrap(),
+            fs::read(temp.path().join("slow.diff")).unwrap()
+        );
+
+        // unchanged chunks take the saved states instead of being decoded
+        write_states_file(&tars[1], &layout, &ChunkStates::from([((12, 3), (1, 2))])).unwrap();
+        let marked = index_states(diff(2, "marked.diff"));
+        assert_eq!(marked[&(12, 3)], (1, 2));
+        assert_eq!(marked[&(10, 2)], slow[&(10, 2)]);
+    }
+}
@@ -6,19 +6,21 @@
 #![feature(mpmc_channel)]
 #![warn(clippy::all, clippy::nursery)]
 
-use anyhow::anyhow;
 use clap::Parser;
-use lazy_regex::regex;
-use log::{debug, info, warn};
+use log::{debug, info};
 use rayon::prelude::*;
-use std::ops::RangeInclusive;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::mpmc::{Receiver, Sender, sync_channel};
 use std::thread::{JoinHandle, spawn};
-use std::{fs, hint};
-use wplace_tools::indexed_png::{read_png_reader, write_png};
-use wplace_tools::tar::ChunksTarReader;
-use wplace_tools::{CHUNK_DIMENSION, CHUNK_LENGTH, Canvas, ChunkNumber, ChunkProcessError, DiffFilesCollector, DirDiffFilesCollector, ExitOnError, SqfsDiffFilesCollector, apply_chunk, diff, quick_capture, set_up_logger, stylized_progress_bar, validate_chunk_checksum, zstd_decompress, AnyhowErrorExt};
+use wplace_tools::chain::{ChainReplay, open_chain_base};
+use wplace_tools::indexed_png::write_png;
+use wplace_tools::layout::ChunkPathLayout;
+use wplace_tools::{
+    CHUNK_DIMENSION, CHUNK_LENGTH, Canvas, ExitOnError, open_diff_source, parse_chunk_string,
+    set_up_logger, stylized_progress_bar,
+};
 use yeet_ops::yeet;
 
 #[derive(clap::Parser)]
@@ -34,7 +36,7 @@ struct Args {
     #[arg(short, long, required = true)]
     diff_source: Vec<PathBuf>,
 
-    /// Path to the initial snapshot (tarball format)
+    /// Path to the initial snapshot (tarball, ZIP or folder)
     #[arg(short, long)]
     base_snapshot: PathBuf,
 
@@ -63,6 +65,9 @@ struct Args {
     /// Only save the stitched images. This implies `--stitch`.
     #[arg(long)]
     only_stitched: bool,
+
+    #[command(flatten, next_help_heading = "Archive layout")]
+    layout: ChunkPathLayout,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -71,14 +76,7 @@ fn main() -> anyhow::Result<()> {
     let chunks = parse_chunk_string(&args.chunk)?;
 
     info!("Collecting diff files...");
-    let diff_source: &(dyn DiffFilesCollector + Send + Sync + 'static) =
-        if args.diff_source.iter().all(|x| x.is_file()) {
-            &SqfsDiffFilesCollector::new(&args.diff_source)?
-        } else if args.diff_source.iter().all(|x| x.is_dir()) {
-            &DirDiffFilesCollector::new(&args.diff_source)?
-        } else {
-            return Err(anyhow!("SquashFS and Dir diff inputs cannot be mixed."));
-        };
+    let diff_source = open_diff_source(&args.diff_source)?;
 
     info!("Diff file count: {}", diff_source.name_iter().len());
     let goal_snapshot = args.at.unwrap_or_else(|| diff_source.last());
@@ -90,92 +88,79 @@ fn main() -> anyhow::Result<()> {
     }
 
     let apply_list_start = diff_source.first();
-    let apply_list = diff_source.range_iter(&apply_list_start, &goal_snapshot);
+    let apply_list = diff_source
+        .range_iter(&apply_list_start, &goal_snapshot)
+        .collect::<Vec<_>>();
     let apply_list_len = apply_list.len();
 
-    info!("Indexing initial tarball...");
-    let tar = ChunksTarReader::open_with_index(&args.base_snapshot)?;
+    info!("Reading base snapshot...");
+    let base = open_chain_base(
+        &args.base_snapshot,
+        &*diff_source,
+        &apply_list,
+        &args.layout,
+    )?;
+    info!("Scanning references...");
+    let mut replay = ChainReplay::for_chunks(
+        base,
+        Arc::clone(&diff_source),
+        apply_list.clone(),
+        chunks.iter().copied(),
+    )?;
+    replay.validate = !args.disable_csum;
+    replay.validate_base = !args.disable_csum;
 
     info!("Retrieving...");
-    let pb = stylized_progress_bar((apply_list_len * chunks.len()) as u64);
+    let pb = stylized_progress_bar(apply_list_len as u64);
 
     let image_saver = ImageSaver::new();
 
-    // Retrieve chunk from the initial tarball for later processes on it.
-    let mut chunks_buf = chunks
-        .iter()
-        .map(|&n| {
-            let a: anyhow::Result<_> = try { (n, retrieve_tar_chunk(&tar, n, true)?) };
-            a
-        })
-        .collect::<Result<Vec<_>, _>>()?;
-
     // sequentially apply .diff files
-    for (idx, name) in apply_list.enumerate() {
+    for (idx, name) in apply_list.into_iter().enumerate() {
+        replay.step()?;
+        pb.inc(1);
         let is_last_snapshot = idx == apply_list_len - 1;
-        let stitch_canvas = match args.stitch | args.only_stitched {
-            true => Some(Canvas::from_chunk_list(chunks_buf.iter().map(|x| x.0))),
-            false => None,
-        };
+        if !args.all && !is_last_snapshot {
+            continue;
+        }
 
         // parallelize if multiple chunks are requested
-        chunks_buf.par_iter_mut().for_each(|(n, chunk_buf)| {
-            pb.inc(1);
-            let result: anyhow::Result<()> = try {
-                let chunk_out = args.out.join(format!("{}-{}", n.0, n.1));
-
-                let mut diff_data = vec![0_u8; CHUNK_LENGTH];
-                let mut diff_file = diff::DiffFile::open(diff_source.reader(&name)?)?;
-                let chunk_index = diff_file.query_chunk(*n)?;
-
-                let entry = match chunk_index {
-                    None => {
-                        // chunk had not been created in this snapshot
-                        info!("Chunk not present in this snapshot '{}', skipping...", name);
-                        // [comment 1]
-                        // When a chunk is not present or being deleted, the chunk buffer needs to
-                        // be reset. Say, one chunk gets deleted here, however the next diff file
-                        // adds this chunk back again; it requires an all-zero buffer to perform its
-                        // applying, or the outdated data will cause a checksum mismatch.
-                        chunk_buf.fill(0);
-                        return;
-                    }
-                    Some(e) => e,
-                };
-
-                if hint::unlikely(entry.is_changed()) {
-                    let portion_reader = diff_file.open_chunk(&entry)?;
-                    zstd_decompress(portion_reader, &mut diff_data)?;
-                    apply_chunk(chunk_buf, <&[_; _]>::try_from(&diff_data[..]).unwrap());
-                    if !args.disable_csum {
-                        validate_chunk_checksum(chunk_buf, entry.checksum)?;
-                    }
-                } else {
-                    // just pass
-                }
-
-                let img_path = chunk_out.join(format!("{name}.png"));
-                if (args.all || is_last_snapshot) && !args.only_stitched {
-                    image_saver.submit(img_path, CHUNK_DIMENSION, chunk_buf.clone());
+        let states = chunks
+            .par_iter()
+            .map(|&n| {
+                let mut buf = vec![0_u8; CHUNK_LENGTH];
+                // Absent chunks are left all-zero, e.g. for stitching.
+                let present = replay.fetch(n, &mut buf)?;
+                Ok((n, present, buf))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if !args.only_stitched {
+            for (n, present, buf) in &states {
+                if !present {
+                    // chunk had not been created in this snapshot
+                    info!("Chunk {n:?} not present in this snapshot '{name}', skipping...");
+                    continue;
                 }
-            };
-            result.exit_with_chunk_context(*n, Some(format!("Diff iso8601 name: {}", name)));
-        });
+                let img_path = args
+                    .out
+                    .join(format!("{}-{}", n.0, n.1))
+                    .join(format!("{name}.png"));
+                image_saver.submit(img_path, CHUNK_DIMENSION, buf.clone());
+            }
+        }
         // save the stitched image
-        if let Some(mut c) = stitch_canvas {
-            let stitch_out = args.out.join("stitched");
-
-            if args.all || is_last_snapshot {
-                for x in &chunks_buf {
-                    c.copy(x.0, <&[_; _]>::try_from(&x.1[..]).unwrap());
-                }
-                let out_file = stitch_out.join(format!("{name}.png"));
-                image_saver.submit(
-                    out_file,
-                    (c.dimension.0 as u32, c.dimension.1 as u32),
-                    c.buf,
-                );
+        if args.stitch || args.only_stitched {
+            let mut c = Canvas::from_chunk_list(chunks.iter().copied());
+            for (n, _, buf) in &states {
+                c.copy(*n, <&[_; _]>::try_from(&buf[..]).unwrap());
             }
+            let out_file = args.out.join("stitched").join(format!("{name}.png"));
+            image_saver.submit(
+                out_file,
+                (c.dimension.0 as u32, c.dimension.1 as u32),
+                c.buf,
+            );
         }
     }
     pb.finish();
@@ -185,60 +170,6 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn parse_chunk_string(s: &str) -> anyhow::Result<Vec<ChunkNumber>> {
-    let mut chunks: Vec<ChunkNumber> = Vec::new();
-    let s = s.chars().filter(|x| !x.is_whitespace()).collect::<String>();
-    let split = s.split(',');
-    for x in split {
-        let p1 = regex!(r"^(\d+)\-(\d+)\.\.(\d+)\-(\d+)$");
-        let p2 = regex!(r"^(\d+)\-(\d+)$");
-        if p1.is_match(x) {
-            let group = quick_capture(x, p1).unwrap();
-            let start: ChunkNumber = (group[0].parse()?, group[1].parse()?);
-            let end: ChunkNumber = (group[2].parse()?, group[3].parse()?);
-            expand_chunks_range(start, end)
-                .iter()
-                .for_each(|&x| chunks.push(x));
-        } else if p2.is_match(x) {
-            let group = quick_capture(x, p2).unwrap();
-            chunks.push((group[0].parse()?, group[1].parse()?));
-        } else {
-            yeet!(anyhow::anyhow!("Malformed chunk string: {}", s))
-        }
-    }
-    Ok(chunks)
-}
-
-/// `start` and `end` represent the two diagonal points.
-fn expand_chunks_range(start: ChunkNumber, end: ChunkNumber) -> Vec<(u16, u16)> {
-    const fn range(n1: u16, n2: u16) -> RangeInclusive<u16> {
-        if n1 < n2 { n1..=n2 } else { n2..=n1 }
-    }
-
-    let x_range = range(start.0, end.0);
-    let y_range = range(start.1, end.1);
-    let mut collected = x_range
-        .flat_map(|x| y_range.clone().map(move |y| (x, y)))
-        .collect::<Vec<_>>();
-    collected.sort();
-    collected
-}
-
-fn retrieve_tar_chunk(
-    snapshot: &ChunksTarReader,
-    n: ChunkNumber,
-    allow_non_exist: bool,
-) -> anyhow::Result<Vec<u8>> {
-    let mut buf = vec![0_u8; CHUNK_LENGTH];
-    if allow_non_exist && !snapshot.map.contains_key(&n) {
-        return Ok(buf);
-    }
-    let chunk_reader = snapshot.open_chunk(n).unwrap()?;
-
-    read_png_reader(chunk_reader, &mut buf)?;
-    Ok(buf)
-}
-
 type WrappedTask = Box<dyn FnOnce() + Send>;
 struct ImageSaver {
     task_tx: Sender<WrappedTask>,
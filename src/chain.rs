@@ -0,0 +1,575 @@
+//! Replay of diff chains: a base snapshot followed by consecutive diff files.
+//!
+//! Chunk states are kept in memory (zstd-compressed), so the whole chain can be walked
+//! through without writing any intermediate snapshot to disk.
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::diff::{DiffFile, IndexEntry, validate_payload_checksum};
+use crate::indexed_png::write_chunk_png_to;
+use crate::layout::ChunkPathLayout;
+use crate::{
+    ChunkFetcher, ChunkNumber, ChunkProcessError, ChunkSelection, DiffFilesCollector, Iso8601Name,
+    ReadSeek, apply_chunk, chunk_buf, open_chunk_fetcher, validate_chunk_checksum, zstd_decompress,
+};
+use anyhow::anyhow;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use yeet_ops::yeet;
+
+/// Compression level of chunk states kept in memory
+pub const STATE_ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone)]
+enum ChunkState {
+    /// Same as in the base snapshot
+    Base,
+    /// Compressed chunk data
+    Compressed(Arc<Vec<u8>>),
+}
+
+/// Properties of the diff being applied
+struct StepContext {
+    first: bool,
+    algorithm: ChecksumAlgorithm,
+    /// Chain positions of `Metadata::references`
+    ref_positions: Vec<Option<usize>>,
+}
+
+#[derive(Clone)]
+struct Slot {
+    state: ChunkState,
+    checksum: u32,
+}
+
+/// Statistics of one replayed diff.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StepStats {
+    /// Number of chunks in the snapshot after this diff
+    pub chunks: usize,
+    /// Chunks carrying diff data
+    pub changed: usize,
+    /// Chunks taken from an earlier state by reference
+    pub references: usize,
+    /// Chunks absent from the previous snapshot
+    pub added: usize,
+    /// Chunks absent from this snapshot but present in the previous one
+    pub deleted: usize,
+    /// Unchanged chunks validated against the base snapshot (first diff only)
+    pub base_checked: usize,
+    /// Compressed diff data read
+    pub payload_bytes: u64,
+    /// Mutated pixels, if recorded in the index (v5+)
+    pub mutated_pixels: u64,
+}
+
+impl StepStats {
+    pub const fn accumulate(&mut self, other: &Self) {
+        self.chunks = other.chunks;
+        self.changed += other.changed;
+        self.references += other.references;
+        self.added += other.added;
+        self.deleted += other.deleted;
+        self.base_checked += other.base_checked;
+        self.payload_bytes += other.payload_bytes;
+        self.mutated_pixels += other.mutated_pixels;
+    }
+}
+
+#[derive(Clone)]
+pub struct ChainReplay {
+    base: Arc<dyn ChunkFetcher + Send + Sync>,
+    source: Arc<dyn DiffFilesCollector + Send + Sync>,
+    names: Vec<Iso8601Name>,
+    applied: usize,
+    /// Chunks present in the current snapshot
+    slots: HashMap<ChunkNumber, Slot>,
+    /// Chain position -> chunks whose state after that diff is referenced later
+    ref_targets: HashMap<usize, HashSet<ChunkNumber>>,
+    /// Chain position -> position of the last diff referencing it
+    ref_last: HashMap<usize, usize>,
+    /// Referenced chunk states; None for absent chunks
+    stash: HashMap<(usize, ChunkNumber), Option<Slot>>,
+    /// The only chunks replayed, if not all
+    subset: Option<Arc<BTreeSet<ChunkNumber>>>,
+    /// Region of all the diffs, if they're filtered; see `Metadata::region`
+    region: Vec<ChunkSelection>,
+    /// Validate checksums of chunks and diff data
+    pub validate: bool,
+    /// Also validate the unchanged chunks of the base snapshot when replaying the first diff
+    pub validate_base: bool,
+}
+
+impl ChainReplay {
+    /// Prepare replaying diffs `names` of `source` (in chain order) on top of `base`.
+    ///
+    /// Reference entries are scanned here; every referenced diff must precede the diff
+    /// referencing it in `names`. Filtered diffs must all have the same region, and `base` must
+    /// have no chunks outside it; open it with [`open_chain_base`].
+    pub fn new(
+        base: Arc<dyn ChunkFetcher + Send + Sync>,
+        source: Arc<dyn DiffFilesCollector + Send + Sync>,
+        names: Vec<Iso8601Name>,
+    ) -> anyhow::Result<Self> {
+        Self::build(base, source, names, None)
+    }
+
+    /// Like [`Self::new`], but only `chunks` are replayed. Their index entries are looked up
+    /// one by one instead of reading whole indices, which suits a few chunks of a long chain.
+    pub fn for_chunks(
+        base: Arc<dyn ChunkFetcher + Send + Sync>,
+        source: Arc<dyn DiffFilesCollector + Send + Sync>,
+        names: Vec<Iso8601Name>,
+        chunks: impl IntoIterator<Item = ChunkNumber>,
+    ) -> anyhow::Result<Self> {
+        let subset = Arc::new(chunks.into_iter().collect());
+        Self::build(base, source, names, Some(subset))
+    }
+
+    fn build(
+        base: Arc<dyn ChunkFetcher + Send + Sync>,
+        source: Arc<dyn DiffFilesCollector + Send + Sync>,
+        names: Vec<Iso8601Name>,
+        subset: Option<Arc<BTreeSet<ChunkNumber>>>,
+    ) -> anyhow::Result<Self> {
+        let mut ref_targets = HashMap::new();
+        let mut ref_last = HashMap::new();
+        let mut region = None;
+        for (pos, name) in names.iter().enumerate() {
+            let mut diff_file = DiffFile::open(source.reader(name)?)?;
+            diff_file.metadata.ensure_whole(name)?;
+            let region = region.get_or_insert_with(|| diff_file.metadata.region.clone());
+            if &diff_file.metadata.region != region {
+                yeet!(anyhow!(
+                    "{name} is filtered to a different region than {}",
+                    names[0]
+                ));
+            }
+            if diff_file.metadata.references.is_empty() {
+                continue;
+            }
+            for entry in read_entries(&mut diff_file, subset.as_deref())? {
+                if entry.reference.is_none() {
+                    continue;
+                }
+                let ref_name = diff_file.referenced_name(&entry)?;
+                let Some(target) = names[..pos].iter().rposition(|x| x == ref_name) else {
+                    yeet!(anyhow!(
+                        "{name} references diff {ref_name}, which is not in the chain before it"
+                    ));
+                };
+                ref_targets
+                    .entry(target)
+                    .or_insert_with(HashSet::new)
+                    .insert((entry.x, entry.y));
+                ref_last.insert(target, pos);
+            }
+        }
+
+        let region = region.unwrap_or_default();
+        let outside = |n: &ChunkNumber| !region.iter().all(|s| s.contains(*n));
+        if let Some(n) = subset.iter().flat_map(|x| x.iter()).find(|n| outside(n)) {
+            yeet!(anyhow!(
+                "Chunk {n:?} is outside the region the diffs are filtered to"
+            ));
+        }
+        if !region.is_empty()
+            && let Some(n) = base.chunks_iter().find(outside)
+        {
+            yeet!(anyhow!(
+                "The base snapshot has chunk {n:?} outside the region the diffs are filtered to; \
+                 use the regional base snapshot exported with them"
+            ));
+        }
+        Ok(Self {
+            base,
+            source,
+            names,
+            applied: 0,
+            slots: HashMap::new(),
+            ref_targets,
+            ref_last,
+            stash: HashMap::new(),
+            subset,
+            region,
+            validate: true,
+            validate_base: false,
+        })
+    }
+
+    pub fn names(&self) -> &[Iso8601Name] {
+        &self.names
+    }
+
+    /// Region the diffs are filtered to; empty if they aren't
+    pub fn region(&self) -> &[ChunkSelection] {
+        &self.region
+    }
+
+    /// Number of diffs applied so far
+    pub const fn applied(&self) -> usize {
+        self.applied
+    }
+
+    pub const fn is_finished(&self) -> bool {
+        self.applied == self.names.len()
+    }
+
+    /// Name of the snapshot after the diffs applied so far; None if it's still the base
+    pub fn current(&self) -> Option<&Iso8601Name> {
+        self.applied.checked_sub(1).map(|i| &self.names[i])
+    }
+
+    /// Apply diffs until the snapshot `name` (inclusive) is reached.
+    pub fn replay_until(
+        &mut self,
+        name: &str,
+        progress: Option<&ProgressBar>,
+    ) -> anyhow::Result<()> {
+        let Some(end) = self.names.iter().position(|x| x == name) else {
+            yeet!(anyhow!("Snapshot {name} is not in the chain"));
+        };
+        if end < self.applied {
+            yeet!(anyhow!("Snapshot {name} has already been passed"));
+        }
+        while self.applied <= end {
+            self.step()?;
+            if let Some(pb) = progress {
+                pb.inc(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Chunks present in the current snapshot, with their checksums
+    pub fn chunks(&self) -> impl Iterator<Item = (ChunkNumber, u32)> + '_ {
+        self.slots.iter().map(|(&n, s)| (n, s.checksum))
+    }
+
+    /// Checksum of chunk `n` in the current snapshot
+    pub fn checksum(&self, n: ChunkNumber) -> Option<u32> {
+        self.slots.get(&n).map(|s| s.checksum)
+    }
+
+    /// Decompress chunk `n` of the current snapshot to `buf`. Returns false if the chunk is
+    /// absent.
+    ///
+    /// Before any diff is applied, this reads the base snapshot.
+    pub fn fetch(&self, n: ChunkNumber, buf: &mut [u8]) -> anyhow::Result<bool> {
+        if self.applied == 0 {
+            return self.base.fetch(n, buf);
+        }
+        let Some(slot) = self.slots.get(&n) else {
+            return Ok(false);
+        };
+        self.load_state(n, Some(&slot.state), buf)?;
+        Ok(true)
+    }
+
+    /// PNG file of chunk `n` in the current snapshot; empty if the chunk is absent.
+    ///
+    /// Chunks unchanged since the base snapshot are copied as is.
+    pub fn fetch_raw(&self, n: ChunkNumber) -> anyhow::Result<Vec<u8>> {
+        if self.applied == 0 {
+            return self.base.fetch_raw(n);
+        }
+        match self.slots.get(&n) {
+            None => Ok(Vec::new()),
+            Some(Slot {
+                state: ChunkState::Base,
+                ..
+            }) => self.base.fetch_raw(n),
+            Some(slot) => {
+                let mut buf = chunk_buf!();
+                self.load_state(n, Some(&slot.state), &mut buf)?;
+                let mut png = Vec::new();
+                write_chunk_png_to(&mut png, &buf)?;
+                Ok(png)
+            }
+        }
+    }
+
+    fn load_state(
+        &self,
+        n: ChunkNumber,
+        state: Option<&ChunkState>,
+        buf: &mut [u8],
+    ) -> anyhow::Result<()> {
+        match state {
+            None => buf.fill(0),
+            Some(ChunkState::Base) => {
+                if !self.base.fetch(n, buf)? {
+                    buf.fill(0);
+                }
+            }
+            Some(ChunkState::Compressed(c)) => zstd_decompress(Cursor::new(&c[..]), buf)?,
+        }
+        Ok(())
+    }
+
+    /// Apply the next diff.
+    ///
+    /// Errors of a single chunk are reported as [`ChunkProcessError`], for the first failing
+    /// chunk in the index order.
+    pub fn step(&mut self) -> anyhow::Result<StepStats> {
+        let pos = self.applied;
+        let Some(name) = self.names.get(pos).cloned() else {
+            yeet!(anyhow!("No more diffs to apply"));
+        };
+        let first = pos == 0;
+
+        let mut diff_file = DiffFile::open(self.source.reader(&name)?)?;
+        let entries = read_entries(&mut diff_file, self.subset.as_deref())?;
+        let context = StepContext {
+            first,
+            algorithm: diff_file.metadata.checksum_algorithm,
+            ref_positions: diff_file
+                .metadata
+                .references
+                .iter()
+                .map(|r| self.names[..pos].iter().rposition(|x| x == r))
+                .collect(),
+        };
+
+        let chunk_error = |n: ChunkNumber, inner: anyhow::Error| ChunkProcessError {
+            inner,
+            chunk_number: n,
+            diff_file: Some(name.clone()),
+        };
+
+        let mut stats = StepStats {
+            chunks: entries.len(),
+            ..Default::default()
+        };
+        if !first {
+            let present = entries.iter().map(|e| (e.x, e.y)).collect::<HashSet<_>>();
+            let before = self.slots.len();
+            self.slots.retain(|n, _| present.contains(n));
+            stats.deleted = before - self.slots.len();
+        }
+
+        let mut work = Vec::new();
+        for e in &entries {
+            let n = (e.x, e.y);
+            if e.is_changed() {
+                if !first && !self.slots.contains_key(&n) {
+                    stats.added += 1;
+                }
+                match e.reference {
+                    Some(_) => stats.references += 1,
+                    None => stats.changed += 1,
+                }
+                stats.payload_bytes += e.len;
+                stats.mutated_pixels += e.stats.map_or(0, |s| s.pixels as u64);
+                work.push(e);
+                continue;
+            }
+            if first {
+                if self.validate && self.validate_base {
+                    work.push(e);
+                }
+                continue;
+            }
+            // unchanged chunks must keep their previous state
+            if self.validate {
+                let consistent = self.slots.get(&n).is_some_and(|s| s.checksum == e.checksum);
+                if !consistent {
+                    yeet!(chunk_error(
+                        n,
+                        anyhow!(
+                            "Chunk is marked as unchanged but differs from the previous snapshot"
+                        )
+                    ));
+                }
+            }
+        }
+
+        let this = &*self;
+        let results = work
+            .par_iter()
+            .map_init(
+                || (this.source.reader(&name), chunk_buf!(), chunk_buf!()),
+                |(reader, buf, diff_buf), &&e| {
+                    let n = (e.x, e.y);
+                    let result: anyhow::Result<Slot> = try {
+                        let reader = reader.as_mut().map_err(|e| anyhow!("{e}"))?;
+                        this.replay_chunk(&e, &context, reader, buf, diff_buf)?;
+                        if this.validate {
+                            validate_chunk_checksum(buf, e.checksum)?;
+                        }
+                        let state = match first && !e.is_changed() {
+                            true => ChunkState::Base,
+                            false => ChunkState::Compressed(Arc::new(zstd::encode_all(
+                                &buf[..],
+                                STATE_ZSTD_LEVEL,
+                            )?)),
+                        };
+                        Slot {
+                            state,
+                            checksum: e.checksum,
+                        }
+                    };
+                    result.map_err(|err| chunk_error(n, err))
+                },
+            )
+            .collect::<Vec<_>>();
+        for (e, r) in work.iter().zip(results) {
+            self.slots.insert((e.x, e.y), r?);
+        }
+        if first {
+            for e in entries.iter().filter(|e| !e.is_changed()) {
+                self.slots.entry((e.x, e.y)).or_insert(Slot {
+                    state: ChunkState::Base,
+                    checksum: e.checksum,
+                });
+            }
+            stats.base_checked = work.len() - stats.changed - stats.references;
+        }
+        // keep the states referenced later, and drop those no longer needed
+        if let Some(targets) = self.ref_targets.get(&pos) {
+            for &n in targets {
+                self.stash.insert((pos, n), self.slots.get(&n).cloned());
+            }
+        }
+        let ref_last = &self.ref_last;
+        self.stash
+            .retain(|(target, _), _| ref_last.get(target).is_some_and(|&last| last > pos));
+
+        self.applied += 1;
+        Ok(stats)
+    }
+
+    /// Reconstruct the state of the chunk of `entry` to `buf`.
+    fn replay_chunk(
+        &self,
+        e: &IndexEntry,
+        context: &StepContext,
+        reader: &mut dyn ReadSeek,
+        buf: &mut [u8],
+        diff_buf: &mut [u8],
+    ) -> anyhow::Result<()> {
+        let n = (e.x, e.y);
+        if let Some(r) = e.reference {
+            let target = context
+                .ref_positions
+                .get(r as usize)
+                .copied()
+                .flatten()
+                .ok_or_else(|| anyhow!("Unresolved reference: {r}"))?;
+            let slot = self
+                .stash
+                .get(&(target, n))
+                .ok_or_else(|| anyhow!("Unresolved reference to diff {}", self.names[target]))?;
+            return self.load_state(n, slot.as_ref().map(|s| &s.state), buf);
+        }
+
+        let previous = match context.first {
+            true => Some(&ChunkState::Base),
+            false => self.slots.get(&n).map(|s| &s.state),
+        };
+        self.load_state(n, previous, buf)?;
+        if !e.is_changed() {
+            return Ok(());
+        }
+
+        reader.seek(SeekFrom::Start(e.pos))?;
+        let mut payload = Vec::with_capacity(e.len as usize);
+        reader.take(e.len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != e.len {
+            yeet!(anyhow!("Truncated diff data"));
+        }
+        if self.validate {
+            validate_payload_checksum(context.algorithm, e, &payload)?;
+        }
+        zstd_decompress(Cursor::new(&payload), diff_buf)?;
+        apply_chunk(buf, (&diff_buf[..]).try_into().unwrap());
+        Ok(())
+    }
+}
+
+/// Open the base snapshot at `path` of the diffs `names` of `source`, for [`ChainReplay`].
+///
+/// Folders are only indexed if the diffs are filtered to a region, so chunks outside it are
+/// found.
+pub fn open_chain_base(
+    path: impl AsRef<Path>,
+    source: &dyn DiffFilesCollector,
+    names: &[Iso8601Name],
+    layout: &ChunkPathLayout,
+) -> anyhow::Result<Arc<dyn ChunkFetcher + Send + Sync>> {
+    let regional = match names.first() {
+        Some(name) => !DiffFile::open(source.reader(name)?)?
+            .metadata
+            .region
+            .is_empty(),
+        None => false,
+    };
+    open_chunk_fetcher(path, regional, layout)
+}
+
+/// Index entries of `diff_file`; only those of `subset` if present.
+fn read_entries<R: Read + Seek>(
+    diff_file: &mut DiffFile<R>,
+    subset: Option<&BTreeSet<ChunkNumber>>,
+) -> anyhow::Result<Vec<IndexEntry>> {
+    let Some(chunks) = subset else {
+        return diff_file.read_index();
+    };
+    let mut entries = Vec::new();
+    for &n in chunks {
+        if let Some(e) = diff_file.query_chunk(n)? {
+            entries.push(e);
+        }
+    }
+    Ok(entries)
+}
+
+/// A snapshot reconstructed in memory from a diff chain: the base snapshot with diffs applied
+/// up to some name.
+pub struct ChainChunkFetcher {
+    replay: ChainReplay,
+    chunks: Vec<ChunkNumber>,
+}
+
+impl ChainChunkFetcher {
+    /// Snapshot after the diffs `replay` has applied. If none is applied, this is the base
+    /// snapshot.
+    pub fn new(replay: ChainReplay) -> Self {
+        let mut chunks = match replay.applied {
+            0 => replay
+                .base
+                .chunks_iter()
+                .filter(|n| replay.subset.as_ref().is_none_or(|x| x.contains(n)))
+                .collect::<Vec<_>>(),
+            _ => replay.slots.keys().copied().collect(),
+        };
+        chunks.sort_unstable();
+        Self { replay, chunks }
+    }
+}
+
+impl ChunkFetcher for ChainChunkFetcher {
+    fn chunks_iter(&self) -> Box<dyn Iterator<Item = ChunkNumber> + Send + '_> {
+        Box::new(self.chunks.iter().copied())
+    }
+
+    fn chunks_len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn fetch(&self, n: ChunkNumber, buf: &mut [u8]) -> anyhow::Result<bool> {
+        self.replay.fetch(n, buf)
+    }
+
+    fn fetch_raw(&self, n: ChunkNumber) -> anyhow::Result<Vec<u8>> {
+        self.replay.fetch_raw(n)
+    }
+
+    /// Changed chunks are encoded on the fly.
+    fn stores_png(&self) -> bool {
+        self.replay.applied == 0
+    }
+}
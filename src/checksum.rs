@@ -1,6 +1,90 @@
+use crate::ChunkNumber;
 use crc_fast::CrcAlgorithm;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Checksums and state digests of chunks
+pub type ChunkStates = HashMap<ChunkNumber, (u32, u64)>;
 
 #[inline(always)]
 pub fn chunk_checksum(data: &[u8]) -> u32 {
     crc_fast::checksum(CrcAlgorithm::Crc32Cksum, data) as _
 }
+
+/// 64-bit digest of a chunk state (CRC-64/NVME), stored in every index entry.
+///
+/// Together with [`chunk_checksum`] this tells chunk states apart; it's not meant to resist
+/// crafted collisions.
+#[inline(always)]
+pub fn state_digest(data: &[u8]) -> u64 {
+    crc_fast::checksum(CrcAlgorithm::Crc64Nvme, data)
+}
+
+/// Algorithm for integrity checks of diff files (payloads, header and index).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Crc32,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Self::Crc32 => Hasher::Crc32(crc_fast::Digest::new(CrcAlgorithm::Crc32Cksum)),
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    /// Length of the full digest in bytes
+    pub const fn digest_len(self) -> usize {
+        match self {
+            Self::Crc32 => 4,
+            Self::Sha256 => 32,
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Short checksum that fits in an index entry.
+    ///
+    /// For SHA-256 this is the first eight bytes of the digest.
+    pub fn short_checksum(self, data: &[u8]) -> u64 {
+        short_digest(&self.digest(data))
+    }
+}
+
+pub enum Hasher {
+    Crc32(crc_fast::Digest),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Crc32(d) => d.update(data),
+            Self::Sha256(d) => Digest::update(d, data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Crc32(d) => (d.finalize() as u32).to_le_bytes().to_vec(),
+            Self::Sha256(d) => d.finalize().to_vec(),
+        }
+    }
+}
+
+/// Take the first (at most) eight bytes of a digest as a little-endian integer.
+pub fn short_digest(digest: &[u8]) -> u64 {
+    let mut bytes = [0_u8; 8];
+    let len = digest.len().min(8);
+    bytes[..len].copy_from_slice(&digest[..len]);
+    u64::from_le_bytes(bytes)
+}
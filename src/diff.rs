@@ -1,25 +1,144 @@
 //! Diff file.
 //!
 //! ## Format
-//! Magic (11B) | Version (u16) | IndexPos (u64) | EntryCount (u32) | Metadata | Diff Data | Sorted Index Entries...
-
-use crate::ChunkNumber;
+//! Magic (11B) | Version (u16) | IndexPos (u64) | EntryCount (u32) | Metadata | Diff Data | Sorted Index Entries... | Digest (v5+)
+//!
+//! The digest covers the header (up to the end of metadata) and the index, computed with
+//! `Metadata::checksum_algorithm`.
+//!
+//! ## Index entry
+//! - v4: X (u16) | Y (u16) | Checksum (u32) | Pos (u64) | Len (u64)
+//! - v5: v4 fields | Reference (u16) | MutatedPixels (u32) | BBox (4 * u16) | PayloadChecksum (u64)
+//!   | StateDigest (u64)
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::{
+    CHUNK_LENGTH, CHUNK_NUMBER_TOTAL, CHUNK_WIDTH, ChunkNumber, ChunkSelection, DiffFilesCollector,
+    Iso8601Name, MUTATION_MASK, PALETTE_INDEX_MASK, zstd_decompress,
+};
 use byteorder::{LE, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Take, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
 use yeet_ops::yeet;
 
 pub const MAGIC: [u8; 11] = *b"wplace-diff";
-pub const VERSION: u16 = 4;
-pub const INDEX_ENTRY_SIZE: u64 = 24;
+pub const VERSION: u16 = 5;
+/// The oldest version that can still be read.
+pub const MIN_VERSION: u16 = 4;
+/// Stored value of [`IndexEntry::reference`] when there's no reference.
+const NO_REFERENCE: u16 = u16::MAX;
+
+/// Size of a fixed-size index entry in the given format version.
+pub const fn index_entry_size(version: u16) -> u64 {
+    match version {
+        4 => 24,
+        _ => 54,
+    }
+}
 
 #[derive(Default, Serialize, Deserialize, Clone)]
-pub struct Metadata {}
+pub struct Metadata {
+    /// Names of the diffs that reference entries point to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<Iso8601Name>,
+    /// Algorithm of payload checksums and the header/index digest (v5+)
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Present if the file only contains one shard of a diff
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard: Option<Shard>,
+    /// Selections the diff has been filtered by; only the chunks selected by all of them are
+    /// kept. Such a diff only applies to a base snapshot without other chunks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub region: Vec<ChunkSelection>,
+}
+
+impl Metadata {
+    /// Fail if the diff `name` is only one shard of a diff, which can't be applied on its own.
+    pub fn ensure_whole(&self, name: &str) -> anyhow::Result<()> {
+        if let Some(shard) = &self.shard {
+            yeet!(anyhow::anyhow!(
+                "{name} is only shard {shard} of a diff; merge the shards first"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Shard `index` of `count`, split by chunk column: chunks with x in
+/// `[index * 2048 / count, (index + 1) * 2048 / count)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub index: u16,
+    pub count: u16,
+    /// Identity of the whole diff, the same in all of its shards
+    pub diff: String,
+}
+
+impl Shard {
+    /// Shard `index` of `count`, with an empty diff identity.
+    pub fn new(index: u16, count: u16) -> anyhow::Result<Self> {
+        if count == 0 || count as usize > CHUNK_NUMBER_TOTAL || index >= count {
+            yeet!(anyhow::anyhow!("Invalid shard: {index}/{count}"));
+        }
+        Ok(Self {
+            index,
+            count,
+            diff: String::new(),
+        })
+    }
+
+    pub fn of_diff(self, diff: impl Into<String>) -> Self {
+        Self {
+            diff: diff.into(),
+            ..self
+        }
+    }
+
+    /// Range of chunk x of this shard
+    pub const fn x_range(&self) -> Range<usize> {
+        let (i, n) = (self.index as usize, self.count as usize);
+        (i * CHUNK_NUMBER_TOTAL / n)..((i + 1) * CHUNK_NUMBER_TOTAL / n)
+    }
+
+    pub fn contains(&self, (x, _): ChunkNumber) -> bool {
+        self.x_range().contains(&(x as usize))
+    }
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
 
-/// Fixed-size index entry (24 bytes)
+    /// Parse `<index>/<count>`, e.g. `0/4`. The diff identity is left empty.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("Malformed shard: {s}"))?;
+        Self::new(index.parse()?, count.parse()?)
+    }
+}
+
+impl Display for Shard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Options for [`DiffFile::open_with_options`].
+#[derive(Default, Clone, Copy, Debug)]
+pub struct OpenOptions {
+    /// Verify the header, index and all payloads against their checksums on opening.
+    pub verify: bool,
+}
+
+/// Fixed-size index entry
 #[derive(Debug, Clone, Copy)]
 pub struct IndexEntry {
     pub x: u16,
@@ -28,17 +147,138 @@ pub struct IndexEntry {
     pub pos: u64,
     /// Length of the compression diff data
     pub len: u64,
+    /// If present, the chunk has no diff data but is exactly the same as itself after the diff
+    /// `Metadata::references[reference]` was applied.
+    pub reference: Option<u16>,
+    /// Change statistics against the parent snapshot (v5+)
+    pub stats: Option<ChangeStats>,
+    /// Checksum of the compressed diff data; zero if there's none (v5+)
+    pub payload_checksum: Option<u64>,
+    /// [`state_digest`](crate::checksum::state_digest) of the chunk after the diff; zero if
+    /// unknown (v5+)
+    pub state_digest: Option<u64>,
+}
+
+/// Statistics of the mutated pixels in a chunk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeStats {
+    /// Number of mutated pixels
+    pub pixels: u32,
+    /// Inclusive bounding box of the mutated pixels: (x_min, y_min, x_max, y_max).
+    /// All zeros if there's no mutated pixel.
+    pub bbox: (u16, u16, u16, u16),
+}
+
+impl ChangeStats {
+    /// Collect statistics from uncompressed diff data.
+    pub fn from_diff_data(diff_data: &[u8]) -> Self {
+        let mut pixels = 0_u32;
+        let (mut x_min, mut y_min, mut x_max, mut y_max) = (u16::MAX, u16::MAX, 0_u16, 0_u16);
+        for (y, row) in diff_data.chunks(CHUNK_WIDTH).enumerate() {
+            for (x, &pix) in row.iter().enumerate() {
+                if pix & MUTATION_MASK == 0 {
+                    continue;
+                }
+                pixels += 1;
+                x_min = x_min.min(x as u16);
+                x_max = x_max.max(x as u16);
+                y_min = y_min.min(y as u16);
+                y_max = y_max.max(y as u16);
+            }
+        }
+        if pixels == 0 {
+            return Self::default();
+        }
+        Self {
+            pixels,
+            bbox: (x_min, y_min, x_max, y_max),
+        }
+    }
 }
 
 impl IndexEntry {
-    /// Determine if chunk is changed by checking data range
+    /// Determine if chunk is changed by checking data range and reference
     pub const fn is_changed(&self) -> bool {
+        self.has_data() || self.reference.is_some()
+    }
+
+    /// Determine if the entry carries diff data
+    pub const fn has_data(&self) -> bool {
         self.pos != 0 || self.len != 0
     }
+
+    fn read_from(mut reader: impl Read, version: u16) -> io::Result<Self> {
+        let x = reader.read_u16::<LE>()?;
+        let y = reader.read_u16::<LE>()?;
+        let checksum = reader.read_u32::<LE>()?;
+        let pos = reader.read_u64::<LE>()?;
+        let len = reader.read_u64::<LE>()?;
+        let reference = match version {
+            4 => None,
+            _ => Some(reader.read_u16::<LE>()?).filter(|&x| x != NO_REFERENCE),
+        };
+        let stats = match version {
+            4 => None,
+            _ => Some(ChangeStats {
+                pixels: reader.read_u32::<LE>()?,
+                bbox: (
+                    reader.read_u16::<LE>()?,
+                    reader.read_u16::<LE>()?,
+                    reader.read_u16::<LE>()?,
+                    reader.read_u16::<LE>()?,
+                ),
+            }),
+        };
+
+        let payload_checksum = match version {
+            4 => None,
+            _ => Some(reader.read_u64::<LE>()?),
+        };
+        let state_digest = match version {
+            4 => None,
+            _ => Some(reader.read_u64::<LE>()?),
+        };
+
+        Ok(Self {
+            x,
+            y,
+            checksum,
+            pos,
+            len,
+            reference,
+            stats,
+            payload_checksum,
+            state_digest,
+        })
+    }
+
+    fn write_to(&self, mut writer: impl Write, version: u16) -> io::Result<()> {
+        writer.write_u16::<LE>(self.x)?;
+        writer.write_u16::<LE>(self.y)?;
+        writer.write_u32::<LE>(self.checksum)?;
+        writer.write_u64::<LE>(self.pos)?;
+        writer.write_u64::<LE>(self.len)?;
+        if version >= 5 {
+            writer.write_u16::<LE>(self.reference.unwrap_or(NO_REFERENCE))?;
+            let stats = self.stats.unwrap_or_default();
+            writer.write_u32::<LE>(stats.pixels)?;
+            writer.write_u16::<LE>(stats.bbox.0)?;
+            writer.write_u16::<LE>(stats.bbox.1)?;
+            writer.write_u16::<LE>(stats.bbox.2)?;
+            writer.write_u16::<LE>(stats.bbox.3)?;
+            writer.write_u64::<LE>(self.payload_checksum.unwrap_or_default())?;
+            writer.write_u64::<LE>(self.state_digest.unwrap_or_default())?;
+        }
+        Ok(())
+    }
 }
 
 pub struct DiffFile<R: Read + Seek> {
     reader: R,
+    /// Format version of this file
+    pub version: u16,
+    /// End of the header (including metadata)
+    pub data_pos: u64,
     /// Position of entries area
     pub index_pos: u64,
     pub entry_count: u32,
@@ -53,7 +293,11 @@ impl DiffFile<BufReader<File>> {
 }
 
 impl<R: Read + Seek> DiffFile<R> {
-    pub fn open(mut reader: R) -> anyhow::Result<Self> {
+    pub fn open(reader: R) -> anyhow::Result<Self> {
+        Self::open_with_options(reader, OpenOptions::default())
+    }
+
+    pub fn open_with_options(mut reader: R, options: OpenOptions) -> anyhow::Result<Self> {
         // 1. Verify Magic
         let mut magic = [0_u8; MAGIC.len()];
         reader.read_exact(&mut magic)?;
@@ -63,7 +307,7 @@ impl<R: Read + Seek> DiffFile<R> {
 
         // 2. Verify Version
         let version = reader.read_u16::<LE>()?;
-        if version != VERSION {
+        if !(MIN_VERSION..=VERSION).contains(&version) {
             yeet!(anyhow::anyhow!("Unsupported version: {}", version));
         }
 
@@ -76,13 +320,92 @@ impl<R: Read + Seek> DiffFile<R> {
         let mut meta_buf = vec![0_u8; meta_len];
         reader.read_exact(&mut meta_buf)?;
         let metadata = serde_json::from_slice(&meta_buf)?;
+        let data_pos = reader.stream_position()?;
 
-        Ok(Self {
+        let mut diff_file = Self {
             reader,
+            version,
+            data_pos,
             index_pos,
             entry_count,
             metadata,
-        })
+        };
+        if options.verify {
+            diff_file.verify()?;
+        }
+        Ok(diff_file)
+    }
+
+    /// Position of the trailing digest
+    pub const fn index_end(&self) -> u64 {
+        self.index_pos + self.entry_count as u64 * index_entry_size(self.version)
+    }
+
+    fn ensure_integrity_data(&self) -> anyhow::Result<()> {
+        if self.version < 5 {
+            yeet!(anyhow::anyhow!(
+                "Version {} has no integrity data",
+                self.version
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the header and index against the trailing digest.
+    pub fn verify_header_and_index(&mut self) -> anyhow::Result<()> {
+        self.ensure_integrity_data()?;
+        let algorithm = self.metadata.checksum_algorithm;
+        let mut hasher = algorithm.hasher();
+        let mut buf = vec![0_u8; 64 * 1024];
+        for (pos, len) in [
+            (0, self.data_pos),
+            (self.index_pos, self.index_end() - self.index_pos),
+        ] {
+            self.reader.seek(SeekFrom::Start(pos))?;
+            let mut remaining = len;
+            while remaining > 0 {
+                let n = remaining.min(buf.len() as u64) as usize;
+                self.reader.read_exact(&mut buf[..n])?;
+                hasher.update(&buf[..n]);
+                remaining -= n as u64;
+            }
+        }
+
+        if hasher.finalize() != self.read_digest()? {
+            yeet!(anyhow::anyhow!("Header or index checksum not matched"));
+        }
+        Ok(())
+    }
+
+    /// Read the trailing digest of the header and index.
+    pub fn read_digest(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.ensure_integrity_data()?;
+        let mut digest = vec![0_u8; self.metadata.checksum_algorithm.digest_len()];
+        self.reader.seek(SeekFrom::Start(self.index_end()))?;
+        self.reader.read_exact(&mut digest)?;
+        Ok(digest)
+    }
+
+    /// Check the diff data of `entry` against its payload checksum.
+    pub fn verify_payload(&mut self, entry: &IndexEntry) -> anyhow::Result<()> {
+        self.ensure_integrity_data()?;
+        let mut payload = Vec::new();
+        self.open_chunk(entry)?.read_to_end(&mut payload)?;
+        if payload.len() as u64 != entry.len {
+            yeet!(anyhow::anyhow!("Truncated diff data"));
+        }
+        validate_payload_checksum(self.metadata.checksum_algorithm, entry, &payload)
+    }
+
+    /// Check the header, index and all payloads.
+    pub fn verify(&mut self) -> anyhow::Result<()> {
+        self.verify_header_and_index()?;
+        for entry in self.collect_index()?.values() {
+            if entry.has_data() {
+                self.verify_payload(entry)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn open_chunk(&mut self, entry: &IndexEntry) -> io::Result<Take<&mut R>> {
@@ -97,8 +420,9 @@ impl<R: Read + Seek> DiffFile<R> {
 
         while low <= high {
             let mid = (low + high) / 2;
-            self.reader
-                .seek(SeekFrom::Start(self.index_pos + mid * INDEX_ENTRY_SIZE))?;
+            self.reader.seek(SeekFrom::Start(
+                self.index_pos + mid * index_entry_size(self.version),
+            ))?;
 
             let entry = self.read_entry_at_current()?;
             let current_coord = (entry.x, entry.y);
@@ -115,19 +439,18 @@ impl<R: Read + Seek> DiffFile<R> {
     }
 
     fn read_entry_at_current(&mut self) -> io::Result<IndexEntry> {
-        let x = self.reader.read_u16::<LE>()?;
-        let y = self.reader.read_u16::<LE>()?;
-        let checksum = self.reader.read_u32::<LE>()?;
-        let pos = self.reader.read_u64::<LE>()?;
-        let len = self.reader.read_u64::<LE>()?;
+        IndexEntry::read_from(&mut self.reader, self.version)
+    }
 
-        Ok(IndexEntry {
-            x,
-            y,
-            checksum,
-            pos,
-            len,
-        })
+    /// Name of the diff a reference entry points to.
+    pub fn referenced_name(&self, entry: &IndexEntry) -> anyhow::Result<&Iso8601Name> {
+        let Some(r) = entry.reference else {
+            yeet!(anyhow::anyhow!("Not a reference entry"));
+        };
+        self.metadata
+            .references
+            .get(r as usize)
+            .ok_or_else(|| anyhow::anyhow!("Reference out of range: {r}"))
     }
 
     /// Collects all index entries from the diff3 file into a HashMap.
@@ -138,50 +461,129 @@ impl<R: Read + Seek> DiffFile<R> {
         self.reader.seek(SeekFrom::Start(self.index_pos))?;
 
         for _ in 0..self.entry_count {
-            let x = self.reader.read_u16::<LE>()?;
-            let y = self.reader.read_u16::<LE>()?;
-            let checksum = self.reader.read_u32::<LE>()?;
-            let pos = self.reader.read_u64::<LE>()?;
-            let len = self.reader.read_u64::<LE>()?;
+            let entry = self.read_entry_at_current()?;
+            map.insert((entry.x, entry.y), entry);
+        }
 
-            let n: ChunkNumber = (x, y);
-            let entry = IndexEntry {
-                x,
-                y,
-                checksum,
-                pos,
-                len,
-            };
+        Ok(map)
+    }
 
-            map.insert(n, entry);
+    /// Read all index entries in the stored order.
+    pub fn read_index(&mut self) -> anyhow::Result<Vec<IndexEntry>> {
+        self.reader.seek(SeekFrom::Start(self.index_pos))?;
+        (0..self.entry_count)
+            .map(|_| Ok(self.read_entry_at_current()?))
+            .collect()
+    }
+
+    /// Check the file layout against `entries` (read by [`Self::read_index`]): index order,
+    /// data ranges and references.
+    ///
+    /// Returns descriptions of the problems found.
+    pub fn check_structure(&mut self, entries: &[IndexEntry]) -> anyhow::Result<Vec<String>> {
+        let mut problems = Vec::new();
+        let file_len = self.reader.seek(SeekFrom::End(0))?;
+
+        let digest_len = match self.version {
+            4 => 0,
+            _ => self.metadata.checksum_algorithm.digest_len() as u64,
+        };
+        if self.index_pos < self.data_pos {
+            problems.push(format!(
+                "Index position {} is inside the header",
+                self.index_pos
+            ));
+        }
+        match (self.index_end() + digest_len).cmp(&file_len) {
+            Ordering::Greater => problems.push("Index exceeds the end of file".into()),
+            Ordering::Less => problems.push("Unexpected data after the index".into()),
+            Ordering::Equal => {}
         }
 
-        Ok(map)
+        for w in entries.windows(2) {
+            if (w[0].x, w[0].y) >= (w[1].x, w[1].y) {
+                problems.push(format!(
+                    "Index is not sorted or has duplicates at chunk {:?}",
+                    (w[1].x, w[1].y)
+                ));
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for e in entries {
+            let n = (e.x, e.y);
+            if let Some(r) = e.reference {
+                if e.has_data() {
+                    problems.push(format!("Reference entry {n:?} has diff data"));
+                }
+                if r as usize >= self.metadata.references.len() {
+                    problems.push(format!("Reference of chunk {n:?} out of range: {r}"));
+                }
+            }
+            if !e.has_data() {
+                continue;
+            }
+            match e.pos.checked_add(e.len) {
+                Some(end) if e.pos >= self.data_pos && end <= self.index_pos && e.len > 0 => {
+                    ranges.push((e.pos, end, n));
+                }
+                _ => problems.push(format!(
+                    "Data range of chunk {n:?} is out of the data area: {}+{}",
+                    e.pos, e.len
+                )),
+            }
+        }
+        ranges.sort_unstable();
+        for w in ranges.windows(2) {
+            if w[1].0 < w[0].1 {
+                problems.push(format!(
+                    "Data ranges of chunk {:?} and {:?} overlap",
+                    w[0].2, w[1].2
+                ));
+            }
+        }
+
+        Ok(problems)
     }
 }
 
+/// Writer of diff files.
+///
+/// Entries have to be added in ascending (x, y) order, so the diff data is laid out in the
+/// same order and identical inputs produce byte-identical files.
 pub struct DiffFileWriter<W: Write + Seek> {
     writer: W,
+    version: u16,
+    /// Header bytes with the placeholders, for computing the digest
+    header: Vec<u8>,
+    checksum_algorithm: ChecksumAlgorithm,
+    reference_count: usize,
     current_diff_data_pos: u64,
     index_entries: Vec<IndexEntry>,
 }
 
 impl<W: Write + Seek> DiffFileWriter<W> {
     pub fn create(mut writer: W, metadata: Metadata, version: u16) -> anyhow::Result<Self> {
-        writer.write_all(&MAGIC)?;
-        writer.write_u16::<LE>(version)?;
-        writer.write_u64::<LE>(0)?; // IndexPos placeholder
-        writer.write_u32::<LE>(0)?; // EntryCount placeholder
+        let mut header = Vec::new();
+        header.write_all(&MAGIC)?;
+        header.write_u16::<LE>(version)?;
+        header.write_u64::<LE>(0)?; // IndexPos placeholder
+        header.write_u32::<LE>(0)?; // EntryCount placeholder
 
         // Write Metadata
         let json = serde_json::to_vec(&metadata)?;
-        writer.write_u32::<LE>(json.len() as u32)?;
-        writer.write_all(&json)?;
+        header.write_u32::<LE>(json.len() as u32)?;
+        header.write_all(&json)?;
 
+        writer.write_all(&header)?;
         let diff_data_pos = writer.stream_position()?;
 
         Ok(Self {
             writer,
+            version,
+            header,
+            checksum_algorithm: metadata.checksum_algorithm,
+            reference_count: metadata.references.len(),
             current_diff_data_pos: diff_data_pos,
             index_entries: Vec::new(),
         })
@@ -195,7 +597,10 @@ impl<W: Write + Seek> DiffFileWriter<W> {
         n: ChunkNumber,
         compressed_diff_data: Option<&[u8]>,
         chunk_checksum: u32,
+        state_digest: u64,
+        stats: ChangeStats,
     ) -> anyhow::Result<()> {
+        self.check_order(n)?;
         let (pos, len) = match compressed_diff_data {
             Some(data) => {
                 let start_pos = self.current_diff_data_pos;
@@ -206,6 +611,9 @@ impl<W: Write + Seek> DiffFileWriter<W> {
             }
             None => (0, 0), // Unchanged status
         };
+        let payload_checksum = compressed_diff_data
+            .map(|x| self.checksum_algorithm.short_checksum(x))
+            .unwrap_or_default();
 
         self.index_entries.push(IndexEntry {
             x: n.0,
@@ -213,34 +621,385 @@ impl<W: Write + Seek> DiffFileWriter<W> {
             checksum: chunk_checksum,
             pos,
             len,
+            reference: None,
+            stats: Some(stats),
+            payload_checksum: Some(payload_checksum),
+            state_digest: Some(state_digest),
+        });
+
+        Ok(())
+    }
+
+    /// Add a chunk entry whose state equals the one after diff `Metadata::references[reference]`.
+    pub fn add_reference(
+        &mut self,
+        n: ChunkNumber,
+        reference: u16,
+        chunk_checksum: u32,
+        state_digest: u64,
+        stats: ChangeStats,
+    ) -> anyhow::Result<()> {
+        if self.version < 5 {
+            yeet!(anyhow::anyhow!(
+                "Reference entries are not supported in version {}",
+                self.version
+            ));
+        }
+        if reference as usize >= self.reference_count {
+            yeet!(anyhow::anyhow!("Reference out of range: {reference}"));
+        }
+        self.check_order(n)?;
+
+        self.index_entries.push(IndexEntry {
+            x: n.0,
+            y: n.1,
+            checksum: chunk_checksum,
+            pos: 0,
+            len: 0,
+            reference: Some(reference),
+            stats: Some(stats),
+            payload_checksum: Some(0),
+            state_digest: Some(state_digest),
         });
 
         Ok(())
     }
 
+    /// Copy entry `e` of `src` without recompressing its diff data, which is validated against
+    /// the payload checksum first.
+    ///
+    /// `reference` is the index into this file's references for a reference entry. Missing
+    /// statistics (v4) are computed from the diff data; for references they're left
+    /// empty. Missing state digests (v4) are left unknown.
+    pub fn copy_entry<R: Read + Seek>(
+        &mut self,
+        src: &mut DiffFile<R>,
+        e: &IndexEntry,
+        reference: Option<u16>,
+    ) -> anyhow::Result<()> {
+        let n = (e.x, e.y);
+        let digest = e.state_digest.unwrap_or_default();
+        if e.reference.is_some() {
+            let r = reference.ok_or_else(|| anyhow::anyhow!("Reference of {n:?} is not mapped"))?;
+            return self.add_reference(n, r, e.checksum, digest, e.stats.unwrap_or_default());
+        }
+        if !e.has_data() {
+            return self.add_entry(n, None, e.checksum, digest, ChangeStats::default());
+        }
+        let mut payload = Vec::with_capacity(e.len as usize);
+        src.open_chunk(e)?.read_to_end(&mut payload)?;
+        validate_payload_checksum(src.metadata.checksum_algorithm, e, &payload)?;
+        let stats = match e.stats {
+            Some(s) => s,
+            None => {
+                let mut buf = vec![0_u8; CHUNK_LENGTH];
+                zstd_decompress(Cursor::new(&payload), &mut buf)?;
+                ChangeStats::from_diff_data(&buf)
+            }
+        };
+        self.add_entry(n, Some(&payload), e.checksum, digest, stats)
+    }
+
+    fn check_order(&self, n: ChunkNumber) -> anyhow::Result<()> {
+        if let Some(last) = self.index_entries.last()
+            && (last.x, last.y) >= n
+        {
+            yeet!(anyhow::anyhow!(
+                "Entries must be added in ascending order: {n:?} after {:?}",
+                (last.x, last.y)
+            ));
+        }
+        Ok(())
+    }
+
     pub fn finalize(mut self) -> anyhow::Result<()> {
-        // 1. Sort entries by (x, y) to enable binary search
-        self.index_entries.sort_by_key(|e| (e.x, e.y));
+        // 1. Entries are already sorted by (x, y), which enables binary search
 
         let index_offset = self.writer.stream_position()?;
         let entry_count = self.index_entries.len() as u32;
 
         // 2. Write Index Entries
+        let mut index = Vec::new();
         for e in &self.index_entries {
-            self.writer.write_u16::<LE>(e.x)?;
-            self.writer.write_u16::<LE>(e.y)?;
-            self.writer.write_u32::<LE>(e.checksum)?;
-            self.writer.write_u64::<LE>(e.pos)?;
-            self.writer.write_u64::<LE>(e.len)?;
+            e.write_to(&mut index, self.version)?;
         }
+        self.writer.write_all(&index)?;
 
         // 3. Update Header placeholders
-        // IndexPos is at offset MAGIC.len() + VERSION.len()
-        let header_pos = MAGIC.len() as u64 + 2;
-        self.writer.seek(SeekFrom::Start(header_pos))?;
-        self.writer.write_u64::<LE>(index_offset)?;
-        self.writer.write_u32::<LE>(entry_count)?;
+        // IndexPos follows Magic and the u16 Version
+        let header_pos = MAGIC.len() + 2;
+        (&mut self.header[header_pos..]).write_u64::<LE>(index_offset)?;
+        (&mut self.header[(header_pos + 8)..]).write_u32::<LE>(entry_count)?;
+
+        // 4. Write Digest of the final header and index
+        if self.version >= 5 {
+            let mut hasher = self.checksum_algorithm.hasher();
+            hasher.update(&self.header);
+            hasher.update(&index);
+            self.writer.write_all(&hasher.finalize())?;
+        }
+
+        self.writer.seek(SeekFrom::Start(header_pos as u64))?;
+        self.writer
+            .write_all(&self.header[header_pos..(header_pos + 12)])?;
+        self.writer.flush()?;
 
         Ok(())
     }
 }
+
+/// Check `payload` (compressed diff data) against the payload checksum of `entry`.
+///
+/// Entries from versions without payload checksums are always accepted.
+pub fn validate_payload_checksum(
+    algorithm: ChecksumAlgorithm,
+    entry: &IndexEntry,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    if let Some(c) = entry.payload_checksum
+        && algorithm.short_checksum(payload) != c
+    {
+        yeet!(anyhow::anyhow!("Payload checksum not matched"));
+    }
+    Ok(())
+}
+
+/// Check the compressed diff data of `entry`: payload checksum, decompressed length,
+/// reserved bits and change statistics.
+///
+/// `buf` is used for decompression.
+pub fn check_payload(
+    algorithm: ChecksumAlgorithm,
+    entry: &IndexEntry,
+    payload: &[u8],
+    buf: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    validate_payload_checksum(algorithm, entry, payload)?;
+
+    buf.clear();
+    zstd::Decoder::new(payload)?
+        .take(CHUNK_LENGTH as u64 + 1)
+        .read_to_end(buf)?;
+    if buf.len() != CHUNK_LENGTH {
+        yeet!(anyhow::anyhow!(
+            "Decompressed diff data has unexpected length: {}",
+            if buf.len() > CHUNK_LENGTH {
+                format!("> {CHUNK_LENGTH}")
+            } else {
+                buf.len().to_string()
+            }
+        ));
+    }
+    if buf
+        .iter()
+        .any(|&x| x & !(MUTATION_MASK | PALETTE_INDEX_MASK) != 0)
+    {
+        yeet!(anyhow::anyhow!("Diff data has reserved bits set"));
+    }
+    if let Some(stats) = entry.stats
+        && stats != ChangeStats::from_diff_data(buf)
+    {
+        yeet!(anyhow::anyhow!("Change statistics not matched"));
+    }
+    Ok(())
+}
+
+/// Determine if the file is a diff file by its magic.
+pub fn is_diff_file(path: impl AsRef<Path>) -> bool {
+    let mut magic = [0_u8; MAGIC.len()];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && magic == MAGIC
+}
+
+/// Lookup of recent chunk states in a diff chain, keyed by chunk number, checksum and
+/// [`state_digest`](crate::checksum::state_digest).
+///
+/// This is used for encoding chunks that return to an earlier state as references. Entries
+/// without a state digest (v4, or copied from v4) are never referenced.
+pub struct RecentStates {
+    /// Names of the diffs in the lookup window, in chain order
+    pub names: Vec<Iso8601Name>,
+    map: HashMap<(ChunkNumber, u32, u64), u16>,
+}
+
+impl RecentStates {
+    /// Collect chunk states from the last `window` diffs of `source`.
+    pub fn collect(source: &dyn DiffFilesCollector, window: usize) -> anyhow::Result<Self> {
+        let total = source.name_iter().len();
+        let window = window.min(NO_REFERENCE as usize);
+        let names = source
+            .name_iter()
+            .skip(total.saturating_sub(window))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut map = HashMap::new();
+        // newer states override older ones
+        for (i, name) in names.iter().enumerate() {
+            let mut diff_file = DiffFile::open(source.reader(name)?)?;
+            for (n, e) in diff_file.collect_index()? {
+                if let Some(digest) = e.state_digest.filter(|&x| x != 0) {
+                    map.insert((n, e.checksum, digest), i as u16);
+                }
+            }
+        }
+        Ok(Self { names, map })
+    }
+
+    /// Returns the index into `names` of the latest diff after which chunk `n` has the state
+    /// with the given checksum and digest.
+    pub fn lookup(&self, n: ChunkNumber, checksum: u32, state_digest: u64) -> Option<u16> {
+        self.map.get(&(n, checksum, state_digest)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Diff file of `version` with an unchanged entry and two entries with diff data
+    fn diff_bytes(version: u16) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = DiffFileWriter::create(&mut cursor, Metadata::default(), version).unwrap();
+        writer
+            .add_entry((600, 0), None, 1, 2, ChangeStats::default())
+            .unwrap();
+        for (n, payload) in [((600, 1), b"first payload"), ((601, 0), b"other payload")] {
+            writer
+                .add_entry(n, Some(payload), 3, 4, ChangeStats::default())
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    fn open_verified(bytes: Vec<u8>) -> anyhow::Result<DiffFile<Cursor<Vec<u8>>>> {
+        DiffFile::open_with_options(Cursor::new(bytes), OpenOptions { verify: true })
+    }
+
+    #[test]
+    fn verify_intact() {
+        let mut diff_file = open_verified(diff_bytes(VERSION)).unwrap();
+        assert_eq!(diff_file.entry_count, 3);
+        diff_file.verify().unwrap();
+    }
+
+    #[test]
+    fn verify_corrupted_payload() {
+        let mut bytes = diff_bytes(VERSION);
+        let mut diff_file = DiffFile::open(Cursor::new(bytes.clone())).unwrap();
+        let entry = diff_file.query_chunk((601, 0)).unwrap().unwrap();
+        bytes[entry.pos as usize + 3] ^= 1;
+
+        // only checked on demand
+        let mut diff_file = DiffFile::open(Cursor::new(bytes.clone())).unwrap();
+        diff_file.verify_header_and_index().unwrap();
+        let e = diff_file.verify().unwrap_err();
+        assert_eq!(e.to_string(), "Payload checksum not matched");
+        let e = open_verified(bytes).err().unwrap();
+        assert_eq!(e.to_string(), "Payload checksum not matched");
+    }
+
+    #[test]
+    fn verify_corrupted_index() {
+        let mut bytes = diff_bytes(VERSION);
+        let diff_file = DiffFile::open(Cursor::new(bytes.clone())).unwrap();
+        // the checksum field of the first entry
+        bytes[diff_file.index_pos as usize + 4] ^= 1;
+
+        let mut diff_file = DiffFile::open(Cursor::new(bytes.clone())).unwrap();
+        let e = diff_file.verify().unwrap_err();
+        assert_eq!(e.to_string(), "Header or index checksum not matched");
+        let e = open_verified(bytes).err().unwrap();
+        assert_eq!(e.to_string(), "Header or index checksum not matched");
+    }
+
+    #[test]
+    fn verify_v4() {
+        let bytes = diff_bytes(4);
+        let mut diff_file = DiffFile::open(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(diff_file.version, 4);
+        assert_eq!(diff_file.read_index().unwrap().len(), 3);
+        let e = diff_file.verify().unwrap_err();
+        assert_eq!(e.to_string(), "Version 4 has no integrity data");
+        let e = open_verified(bytes).err().unwrap();
+        assert_eq!(e.to_string(), "Version 4 has no integrity data");
+    }
+
+    #[test]
+    fn change_stats() {
+        let mut diff_data = vec![0_u8; CHUNK_LENGTH];
+        assert_eq!(
+            ChangeStats::from_diff_data(&diff_data),
+            ChangeStats::default()
+        );
+
+        // a 3x2 rectangle at (10, 20) and a single pixel at (999, 5); a palette index without
+        // the mutation bit isn't a change
+        for y in 20..22 {
+            for x in 10..13 {
+                diff_data[y * CHUNK_WIDTH + x] = MUTATION_MASK | 3;
+            }
+        }
+        diff_data[5 * CHUNK_WIDTH + 999] = MUTATION_MASK;
+        diff_data[900 * CHUNK_WIDTH] = 3;
+        assert_eq!(
+            ChangeStats::from_diff_data(&diff_data),
+            ChangeStats {
+                pixels: 7,
+                bbox: (10, 5, 999, 21),
+            }
+        );
+    }
+
+    fn structure_problems(bytes: Vec<u8>) -> Vec<String> {
+        let mut diff_file = DiffFile::open(Cursor::new(bytes)).unwrap();
+        let entries = diff_file.read_index().unwrap();
+        diff_file.check_structure(&entries).unwrap()
+    }
+
+    #[test]
+    fn check_structure() {
+        assert!(structure_problems(diff_bytes(VERSION)).is_empty());
+        assert!(structure_problems(diff_bytes(4)).is_empty());
+
+        let mut truncated = diff_bytes(VERSION);
+        truncated.pop();
+        assert_eq!(
+            structure_problems(truncated),
+            ["Index exceeds the end of file"]
+        );
+
+        // the second payload starts inside the first one
+        let mut overlapping = diff_bytes(VERSION);
+        let diff_file = DiffFile::open(Cursor::new(overlapping.clone())).unwrap();
+        let entry_size = index_entry_size(VERSION) as usize;
+        let pos_field = |i: usize| diff_file.index_pos as usize + i * entry_size + 8;
+        let first_pos = u64::from_le_bytes(overlapping[pos_field(1)..][..8].try_into().unwrap());
+        overlapping[pos_field(2)..][..8].copy_from_slice(&(first_pos + 1).to_le_bytes());
+        assert_eq!(
+            structure_problems(overlapping),
+            ["Data ranges of chunk (600, 1) and (601, 0) overlap"]
+        );
+
+        // EntryCount follows Magic, Version and IndexPos
+        let count_field = MAGIC.len() + 2 + 8;
+        let mut too_few = diff_bytes(VERSION);
+        too_few[count_field..][..4].copy_from_slice(&2_u32.to_le_bytes());
+        assert_eq!(
+            structure_problems(too_few),
+            ["Unexpected data after the index"]
+        );
+        let mut too_many = diff_bytes(VERSION);
+        too_many[count_field..][..4].copy_from_slice(&4_u32.to_le_bytes());
+        let mut diff_file = DiffFile::open(Cursor::new(too_many)).unwrap();
+        assert!(diff_file.read_index().is_err());
+        let entries = diff_file.read_index().unwrap_or_default();
+        assert_eq!(
+            diff_file.check_structure(&entries).unwrap(),
+            ["Index exceeds the end of file"]
+        );
+    }
+}
@@ -177,6 +177,10 @@ pub fn read_png_reader(reader: impl BufRead + Seek, index_buf: &mut [u8]) -> any
 
 #[inline(always)]
 pub fn write_chunk_png(path: impl AsRef<Path>, buf: &[u8]) -> anyhow::Result<()> {
+    write_chunk_png_to(BufWriter::new(File::create(path)?), buf)
+}
+
+pub fn write_chunk_png_to(writer: impl Write, buf: &[u8]) -> anyhow::Result<()> {
     let mut img_info = Info::with_size(1000, 1000);
     img_info.bit_depth = BitDepth::Eight;
     img_info.color_type = ColorType::Indexed;
@@ -184,7 +188,6 @@ pub fn write_chunk_png(path: impl AsRef<Path>, buf: &[u8]) -> anyhow::Result<()>
     img_info.trns = Some(Cow::from(&[0_u8]));
     img_info.palette = Some(Cow::Borrowed(PALETTE_DATA_IN_PNG.as_ref()));
 
-    let writer = BufWriter::new(File::create(path)?);
     let encoder = png::Encoder::with_info(writer, img_info)?;
     let mut writer = encoder.write_header()?;
     writer.write_image_data(buf)?;
@@ -0,0 +1,311 @@
+//! Paths of chunk files in snapshot archives.
+
+use crate::ChunkNumber;
+use anyhow::anyhow;
+use lazy_regex::regex;
+use log::warn;
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::{fmt, iter};
+use yeet_ops::yeet;
+
+/// Max entries listed when reporting unexpected entries
+const MAX_REPORTED_ENTRIES: usize = 5;
+
+/// Root directory of the chunk files in an archive
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum RootDir {
+    /// Any single directory, e.g. `2025-08-09T20-01-14.231Z/` in wplace-archives tarballs
+    #[default]
+    Auto,
+    /// Chunk files are at the top level
+    None,
+    Named(String),
+}
+
+impl FromStr for RootDir {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "none" => Self::None,
+            _ if s.is_empty() || s.contains('/') => yeet!(anyhow!("Invalid root directory: {s}")),
+            _ => Self::Named(s.into()),
+        })
+    }
+}
+
+/// Where chunk files are in a snapshot archive: `<root>/<pattern>.<extension>`.
+///
+/// The default is the wplace-archives layout `<root>/{x}/{y}.png`.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Args)]
+pub struct ChunkPathLayout {
+    /// Path of chunk files inside the root directory of archives, without the extension.
+    /// `{x}` and `{y}` stand for the chunk number
+    #[arg(long = "layout-pattern", global = true, default_value = "{x}/{y}")]
+    pub pattern: String,
+
+    /// Root directory of chunk files in archives: `auto` (any single directory), `none` (top
+    /// level), or a directory name
+    #[arg(long = "layout-root", global = true, default_value = "auto")]
+    pub root: RootDir,
+
+    /// Extension of chunk files in archives
+    #[arg(long = "layout-extension", global = true, default_value = "png")]
+    pub extension: String,
+}
+
+impl Default for ChunkPathLayout {
+    fn default() -> Self {
+        Self {
+            pattern: "{x}/{y}".into(),
+            root: RootDir::Auto,
+            extension: "png".into(),
+        }
+    }
+}
+
+impl Display for ChunkPathLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.root {
+            RootDir::Auto => write!(f, "*/")?,
+            RootDir::None => {}
+            RootDir::Named(name) => write!(f, "{name}/")?,
+        }
+        write!(f, "{}", self.pattern)?;
+        if !self.extension.is_empty() {
+            write!(f, ".{}", self.extension)?;
+        }
+        Ok(())
+    }
+}
+
+impl ChunkPathLayout {
+    pub fn matcher(&self) -> anyhow::Result<ChunkPathMatcher> {
+        let mut parts = self.pattern.split('{');
+        let mut expr = String::from("^");
+        match &self.root {
+            RootDir::Auto => expr.push_str("(?P<root>[^/]+)/"),
+            RootDir::None => {}
+            RootDir::Named(name) => expr.push_str(&format!("(?P<root>{})/", regex::escape(name))),
+        }
+        expr.push_str(&regex::escape(parts.next().unwrap_or_default()));
+        let (mut has_x, mut has_y) = (false, false);
+        for part in parts {
+            let (placeholder, literal) = part
+                .split_once('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in pattern: {}", self.pattern))?;
+            let seen = match placeholder {
+                "x" => &mut has_x,
+                "y" => &mut has_y,
+                _ => yeet!(anyhow!("Unknown placeholder in pattern: {{{placeholder}}}")),
+            };
+            if *seen {
+                yeet!(anyhow!(
+                    "Duplicate placeholder in pattern: {{{placeholder}}}"
+                ));
+            }
+            *seen = true;
+            expr.push_str(&format!(r"(?P<{placeholder}>\d+)"));
+            expr.push_str(&regex::escape(literal));
+        }
+        if !has_x || !has_y {
+            yeet!(anyhow!(
+                "Pattern must contain both {{x}} and {{y}}: {}",
+                self.pattern
+            ));
+        }
+        if !self.extension.is_empty() {
+            expr.push_str(&format!(r"\.{}", regex::escape(&self.extension)));
+        }
+        expr.push('$');
+        Ok(ChunkPathMatcher {
+            regex: Regex::new(&expr)?,
+            layout: self.to_string(),
+            root: None,
+            unexpected: Vec::new(),
+            unexpected_count: 0,
+        })
+    }
+}
+
+/// Matches entry paths of an archive against a [`ChunkPathLayout`], and keeps track of the
+/// entries not matching.
+pub struct ChunkPathMatcher {
+    regex: Regex,
+    layout: String,
+    /// Root directory of the first matched chunk file
+    root: Option<String>,
+    unexpected: Vec<String>,
+    unexpected_count: usize,
+}
+
+impl ChunkPathMatcher {
+    /// Chunk number of the file at `path`. Leading `./` is ignored, and directories are
+    /// skipped; other paths not matching the layout are recorded as unexpected.
+    pub fn match_path(&mut self, path: &str) -> Option<ChunkNumber> {
+        let path = path.trim_start_matches("./");
+        if path.is_empty() || path.ends_with('/') {
+            return None;
+        }
+        let n = self.regex.captures(path).and_then(|c| {
+            if let Some(root) = c.name("root").map(|x| x.as_str()) {
+                // all chunk files are in the same root
+                match &self.root {
+                    Some(r) if r != root => return None,
+                    Some(_) => {}
+                    None => self.root = Some(root.into()),
+                }
+            }
+            Some((c["x"].parse().ok()?, c["y"].parse().ok()?))
+        });
+        if n.is_none() {
+            self.record_unexpected(path);
+        }
+        n
+    }
+
+    /// Record an entry which is not a chunk file.
+    pub fn record_unexpected(&mut self, path: &str) {
+        self.unexpected_count += 1;
+        if self.unexpected.len() < MAX_REPORTED_ENTRIES {
+            self.unexpected.push(path.into());
+        }
+    }
+
+    /// Root directory of the chunk files; None if the layout has no root or nothing matched
+    pub fn root(&self) -> Option<&str> {
+        self.root.as_deref()
+    }
+
+    /// Check the result after all entries of `archive` are matched: it's an error if no chunk
+    /// files are found, and unexpected entries are reported as a warning.
+    pub fn finish(&self, archive: &str, chunk_count: usize) -> anyhow::Result<()> {
+        let examples = || {
+            self.unexpected
+                .iter()
+                .map(|x| x.as_str())
+                .chain(iter::once("...").filter(|_| self.unexpected_count > self.unexpected.len()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if chunk_count == 0 && self.unexpected_count > 0 {
+            yeet!(anyhow!(
+                "No chunk files in {archive} match the layout {}; found: {}",
+                self.layout,
+                examples()
+            ));
+        }
+        if self.unexpected_count > 0 {
+            warn!(
+                "Skipped {} entries of {archive} not matching the layout {}: {}",
+                self.unexpected_count,
+                self.layout,
+                examples()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(pattern: &str, root: &str, extension: &str) -> ChunkPathLayout {
+        ChunkPathLayout {
+            pattern: pattern.into(),
+            root: root.parse().unwrap(),
+            extension: extension.into(),
+        }
+    }
+
+    fn error(layout: &ChunkPathLayout) -> String {
+        layout.matcher().err().unwrap().to_string()
+    }
+
+    #[test]
+    fn root_auto() {
+        let mut m = ChunkPathLayout::default().matcher().unwrap();
+        assert_eq!(m.match_path("./2025-08-09T20-01-14.231Z/"), None);
+        assert_eq!(m.match_path("./2025-08-09T20-01-14.231Z/12/"), None);
+        assert_eq!(
+            m.match_path("./2025-08-09T20-01-14.231Z/12/34.png"),
+            Some((12, 34))
+        );
+        assert_eq!(m.root(), Some("2025-08-09T20-01-14.231Z"));
+        // chunk files of another root are unexpected
+        assert_eq!(m.match_path("other/12/35.png"), None);
+        assert_eq!(
+            m.match_path("2025-08-09T20-01-14.231Z/12/35.png"),
+            Some((12, 35))
+        );
+        // chunk files need a root
+        assert_eq!(m.match_path("12/36.png"), None);
+        assert_eq!(m.unexpected_count, 2);
+        assert_eq!(ChunkPathLayout::default().to_string(), "*/{x}/{y}.png");
+    }
+
+    #[test]
+    fn root_none() {
+        let mut m = layout("{x}/{y}", "none", "png").matcher().unwrap();
+        assert_eq!(m.match_path("12/34.png"), Some((12, 34)));
+        assert_eq!(m.match_path("./12/35.png"), Some((12, 35)));
+        assert_eq!(m.match_path("root/12/36.png"), None);
+        assert_eq!(m.match_path("12/37.webp"), None);
+        assert_eq!(m.match_path("12/x.png"), None);
+        assert_eq!(m.root(), None);
+        assert_eq!(m.unexpected_count, 3);
+    }
+
+    #[test]
+    fn root_named() {
+        let mut m = layout("tiles/{y}_{x}", "snap.v2", "").matcher().unwrap();
+        assert_eq!(m.match_path("snap.v2/tiles/34_12"), Some((12, 34)));
+        // the root name is matched literally
+        assert_eq!(m.match_path("snapxv2/tiles/34_12"), None);
+        assert_eq!(m.match_path("other/tiles/34_12"), None);
+        assert_eq!(m.match_path("snap.v2/tiles/34_12.png"), None);
+        assert_eq!(m.root(), Some("snap.v2"));
+    }
+
+    #[test]
+    fn chunk_number_out_of_range() {
+        let mut m = layout("{x}-{y}", "none", "png").matcher().unwrap();
+        assert_eq!(m.match_path("65535-0.png"), Some((65535, 0)));
+        assert_eq!(m.match_path("65536-0.png"), None);
+        assert_eq!(m.unexpected_count, 1);
+    }
+
+    #[test]
+    fn bad_patterns() {
+        assert!(error(&layout("{x}/{y", "auto", "png")).starts_with("Unclosed placeholder"));
+        assert!(error(&layout("{x}/{z}", "auto", "png")).starts_with("Unknown placeholder"));
+        assert!(error(&layout("{x}/{x}/{y}", "auto", "png")).starts_with("Duplicate placeholder"));
+        assert!(error(&layout("{x}", "auto", "png")).starts_with("Pattern must contain both"));
+        assert!(error(&layout("tiles", "auto", "png")).starts_with("Pattern must contain both"));
+        assert!("".parse::<RootDir>().is_err());
+        assert!("a/b".parse::<RootDir>().is_err());
+    }
+
+    #[test]
+    fn finish() {
+        let mut m = ChunkPathLayout::default().matcher().unwrap();
+        for i in 0..7 {
+            m.match_path(&format!("root/{i}.txt"));
+        }
+        assert_eq!(m.unexpected.len(), MAX_REPORTED_ENTRIES);
+        let e = m.finish("a.tar", 0).unwrap_err().to_string();
+        assert!(e.starts_with("No chunk files in a.tar match the layout */{x}/{y}.png"));
+        assert!(e.ends_with("root/4.txt, ..."));
+        // unexpected entries next to chunk files are only warned about
+        m.finish("a.tar", 1).unwrap();
+        ChunkPathLayout::default()
+            .matcher()
+            .unwrap()
+            .finish("a.tar", 0)
+            .unwrap();
+    }
+}
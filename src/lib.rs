@@ -5,14 +5,17 @@
 #![feature(likely_unlikely)]
 #![warn(clippy::all, clippy::nursery)]
 
+pub mod chain;
 pub mod checksum;
 pub mod diff;
 pub mod indexed_png;
+pub mod layout;
 pub mod tar;
 pub mod zip;
 
 use crate::checksum::chunk_checksum;
 use crate::indexed_png::{read_png, read_png_reader, write_png};
+use crate::layout::ChunkPathLayout;
 use crate::tar::ChunksTarReader;
 use anyhow::anyhow;
 use indicatif::{ProgressBar, ProgressStyle};
@@ -20,13 +23,15 @@ use lazy_regex::regex;
 use log::error;
 use pathdiff::diff_paths;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use squashfs_reader::FileSystem;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env::set_var;
 use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
@@ -142,11 +147,7 @@ pub fn collect_chunks(
         ) else {
             continue;
         };
-        if let Some(ref r) = tiles_range {
-            if (r.x_min..=r.x_max).contains(&c1) && (r.y_min..=r.y_max).contains(&c2) {
-                collected.push((c1, c2));
-            }
-        } else {
+        if tiles_range.is_none_or(|r| r.contains((c1, c2))) {
             collected.push((c1, c2));
         }
     }
@@ -165,7 +166,7 @@ pub fn stylized_progress_bar(len: u64) -> ProgressBar {
     pb
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TilesRange {
     pub x_min: u16,
     pub x_max: u16,
@@ -186,6 +187,130 @@ impl TilesRange {
             y_max: split[3].parse().ok()?,
         })
     }
+
+    pub fn contains(&self, (x, y): ChunkNumber) -> bool {
+        (self.x_min..=self.x_max).contains(&x) && (self.y_min..=self.y_max).contains(&y)
+    }
+}
+
+/// Parse chunks in the format `x1-y1,x2-y2,x3-y3,...` or `x1-y1..x2-y2`.
+pub fn parse_chunk_string(s: &str) -> anyhow::Result<Vec<ChunkNumber>> {
+    let mut chunks: Vec<ChunkNumber> = Vec::new();
+    let s = s.chars().filter(|x| !x.is_whitespace()).collect::<String>();
+    let split = s.split(',');
+    for x in split {
+        let p1 = regex!(r"^(\d+)\-(\d+)\.\.(\d+)\-(\d+)$");
+        let p2 = regex!(r"^(\d+)\-(\d+)$");
+        if p1.is_match(x) {
+            let group = quick_capture(x, p1).unwrap();
+            let start: ChunkNumber = (group[0].parse()?, group[1].parse()?);
+            let end: ChunkNumber = (group[2].parse()?, group[3].parse()?);
+            expand_chunks_range(start, end)
+                .iter()
+                .for_each(|&x| chunks.push(x));
+        } else if p2.is_match(x) {
+            let group = quick_capture(x, p2).unwrap();
+            chunks.push((group[0].parse()?, group[1].parse()?));
+        } else {
+            yeet!(anyhow::anyhow!("Malformed chunk string: {}", s))
+        }
+    }
+    Ok(chunks)
+}
+
+/// `start` and `end` represent the two diagonal points.
+fn expand_chunks_range(start: ChunkNumber, end: ChunkNumber) -> Vec<(u16, u16)> {
+    const fn range(n1: u16, n2: u16) -> RangeInclusive<u16> {
+        if n1 < n2 { n1..=n2 } else { n2..=n1 }
+    }
+
+    let x_range = range(start.0, end.0);
+    let y_range = range(start.1, end.1);
+    let mut collected = x_range
+        .flat_map(|x| y_range.clone().map(move |y| (x, y)))
+        .collect::<Vec<_>>();
+    collected.sort();
+    collected
+}
+
+/// Read a chunk list file. Each line is in the format of [`parse_chunk_string`]; empty lines
+/// and lines starting with `#` are skipped.
+pub fn read_chunk_list(path: impl AsRef<Path>) -> anyhow::Result<Vec<ChunkNumber>> {
+    let mut chunks = Vec::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        chunks.extend(parse_chunk_string(line)?);
+    }
+    Ok(chunks)
+}
+
+/// Read a chunk mask image. Pixel (x, y) of the image selects chunk (x, y) if it's not
+/// transparent, or not black for images without an alpha channel.
+pub fn read_chunk_mask(path: impl AsRef<Path>) -> anyhow::Result<Vec<ChunkNumber>> {
+    let mut decoder = png::Decoder::new(File::open_buffered(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    if width as usize > CHUNK_NUMBER_TOTAL || height as usize > CHUNK_NUMBER_TOTAL {
+        yeet!(anyhow!(
+            "Mask image is larger than {CHUNK_NUMBER_TOTAL}x{CHUNK_NUMBER_TOTAL}: {width}x{height}"
+        ));
+    }
+    let (color_type, _) = reader.output_color_type();
+    let mut buf = vec![
+        0_u8;
+        reader
+            .output_buffer_size()
+            .ok_or_else(|| anyhow!("Cannot read output buffer size"))?
+    ];
+    let info = reader.next_frame(&mut buf)?;
+    let samples = color_type.samples();
+    let selected = |pixel: &[u8]| match color_type {
+        png::ColorType::GrayscaleAlpha | png::ColorType::Rgba => pixel[samples - 1] != 0,
+        _ => pixel.iter().any(|&x| x != 0),
+    };
+
+    let mut chunks = Vec::new();
+    for (y, row) in buf
+        .chunks_exact(info.line_size)
+        .take(height as usize)
+        .enumerate()
+    {
+        for (x, pixel) in row.chunks_exact(samples).take(width as usize).enumerate() {
+            if selected(pixel) {
+                chunks.push((x as u16, y as u16));
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+/// Chunks selected by tiles ranges and/or a chunk list
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSelection {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiles_ranges: Vec<TilesRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<BTreeSet<ChunkNumber>>,
+}
+
+impl ChunkSelection {
+    pub const fn is_empty(&self) -> bool {
+        self.tiles_ranges.is_empty() && self.chunks.is_none()
+    }
+
+    /// Whether `n` is selected by any of the ranges or the list. An empty selection selects
+    /// all chunks.
+    pub fn contains(&self, n: ChunkNumber) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        self.tiles_ranges.iter().any(|r| r.contains(n))
+            || self.chunks.as_ref().is_some_and(|c| c.contains(&n))
+    }
 }
 
 /// Build the specified chunk file and create its parent folder if necessary.
@@ -197,6 +322,15 @@ pub fn new_chunk_file(root: impl AsRef<Path>, (x, y): ChunkNumber, ext: &str) ->
     path
 }
 
+/// Directory containing `path`, for creating temporary files next to it. `.` if it has no
+/// parent.
+pub fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    }
+}
+
 pub fn set_up_logger() {
     if env::var("RUST_LOG").is_err() {
         unsafe {
@@ -224,6 +358,28 @@ pub fn extract_datetime(s: impl AsRef<OsStr>) -> Option<Iso8601Name> {
     )
 }
 
+/// Milliseconds since the Unix epoch of a snapshot name, e.g. `2025-08-10T00-50-04.021Z`.
+pub fn name_timestamp(name: &str) -> Option<i64> {
+    let captures = quick_capture(
+        name,
+        regex!(r"^(\d{4})-(\d{2})-(\d{2})T(\d{2})-(\d{2})-(\d{2})\.(\d{3})Z$"),
+    )?;
+    let n = captures
+        .iter()
+        .map(|x| x.parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let (year, month, day) = (n[0], n[1], n[2]);
+    // days from the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some((((days * 24 + n[3]) * 60 + n[4]) * 60 + n[5]) * 1000 + n[6])
+}
+
 #[inline(always)]
 pub fn apply_chunk(base: &mut [u8], diff_data: &[u8; CHUNK_LENGTH]) {
     for (base_pix, &diff_pix) in base.iter_mut().zip(diff_data.iter()) {
@@ -318,7 +474,8 @@ impl<T> AnyhowErrorExt<T> for anyhow::Result<T> {
             inner: e,
             chunk_number: n,
             diff_file: diff_file.map(|x| format!("{}", x.as_ref().display())),
-        }).exit_on_error()
+        })
+        .exit_on_error()
     }
 }
 
@@ -404,6 +561,8 @@ impl Display for ChunkProcessError {
     }
 }
 
+impl std::error::Error for ChunkProcessError {}
+
 pub trait ChunkFetcher {
     fn chunks_iter(&self) -> Box<dyn Iterator<Item = ChunkNumber> + Send + '_>;
 
@@ -412,6 +571,34 @@ pub trait ChunkFetcher {
     fn fetch(&self, n: ChunkNumber, buf: &mut [u8]) -> anyhow::Result<bool>;
 
     fn fetch_raw(&self, n: ChunkNumber) -> anyhow::Result<Vec<u8>>;
+
+    /// Whether [`Self::fetch_raw`] returns the stored PNG files as they are. Then equal PNG
+    /// files can be taken as equal chunks without decoding them.
+    fn stores_png(&self) -> bool {
+        true
+    }
+}
+
+impl<T: ChunkFetcher + ?Sized> ChunkFetcher for Arc<T> {
+    fn chunks_iter(&self) -> Box<dyn Iterator<Item = ChunkNumber> + Send + '_> {
+        (**self).chunks_iter()
+    }
+
+    fn chunks_len(&self) -> usize {
+        (**self).chunks_len()
+    }
+
+    fn fetch(&self, n: ChunkNumber, buf: &mut [u8]) -> anyhow::Result<bool> {
+        (**self).fetch(n, buf)
+    }
+
+    fn fetch_raw(&self, n: ChunkNumber) -> anyhow::Result<Vec<u8>> {
+        (**self).fetch_raw(n)
+    }
+
+    fn stores_png(&self) -> bool {
+        (**self).stores_png()
+    }
 }
 
 pub struct DirChunkFetcher {
@@ -468,10 +655,15 @@ pub struct TarChunkFetcher {
 }
 
 impl TarChunkFetcher {
-    pub fn new(tar: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let reader = ChunksTarReader::open_with_index(tar)?;
+    pub fn new(tar: impl AsRef<Path>, layout: &ChunkPathLayout) -> anyhow::Result<Self> {
+        let reader = ChunksTarReader::open_with_index(tar, layout)?;
         Ok(Self { reader })
     }
+
+    /// Name of the root directory in the tarball
+    pub fn root_name(&self) -> &str {
+        &self.reader.root_name
+    }
 }
 
 impl ChunkFetcher for TarChunkFetcher {
@@ -484,23 +676,83 @@ impl ChunkFetcher for TarChunkFetcher {
     }
 
     fn fetch(&self, n: ChunkNumber, buf: &mut [u8]) -> anyhow::Result<bool> {
-        let Some(c) = self.reader.open_chunk(n) else {
+        let Some(png) = self.reader.read_chunk(n) else {
             return Ok(false);
         };
-        read_png_reader(c?, buf)?;
+        read_png_reader(io::Cursor::new(png?), buf)?;
         Ok(true)
     }
 
     fn fetch_raw(&self, n: ChunkNumber) -> anyhow::Result<Vec<u8>> {
-        let Some(c) = self.reader.open_chunk(n) else {
+        Ok(self.reader.read_chunk(n).transpose()?.unwrap_or_default())
+    }
+}
+
+/// Chunks in a ZIP with stored (uncompressed) entries
+pub struct ZipChunkFetcher {
+    path: PathBuf,
+    map: zip::ChunkIndexMap,
+}
+
+impl ZipChunkFetcher {
+    pub fn new(zip: impl AsRef<Path>, layout: &ChunkPathLayout) -> anyhow::Result<Self> {
+        Ok(Self {
+            map: zip::collect_zip_entries(&zip, layout)?,
+            path: zip.as_ref().into(),
+        })
+    }
+}
+
+impl ChunkFetcher for ZipChunkFetcher {
+    fn chunks_iter(&self) -> Box<dyn Iterator<Item = ChunkNumber> + Send + '_> {
+        Box::new(self.map.keys().copied())
+    }
+
+    fn chunks_len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn fetch(&self, n: ChunkNumber, buf: &mut [u8]) -> anyhow::Result<bool> {
+        let Some(&(pos, len)) = self.map.get(&n) else {
+            return Ok(false);
+        };
+        read_png_reader(open_file_range(&self.path, pos, len)?, buf)?;
+        Ok(true)
+    }
+
+    fn fetch_raw(&self, n: ChunkNumber) -> anyhow::Result<Vec<u8>> {
+        let Some(&(pos, len)) = self.map.get(&n) else {
             return Ok(vec![]);
         };
         let mut vec = Vec::new();
-        io::copy(&mut c?, &mut vec)?;
+        open_file_range(&self.path, pos, len)?.read_to_end(&mut vec)?;
         Ok(vec)
     }
 }
 
+/// Open a snapshot as a [`ChunkFetcher`]. Tarball/ZIP/folder is supported; see
+/// [`tar::is_tarball`] for the tarball forms.
+///
+/// `layout` applies to tarballs and ZIPs, and `index_all` only to folders; see
+/// [`DirChunkFetcher::new`].
+pub fn open_chunk_fetcher(
+    path: impl AsRef<Path>,
+    index_all: bool,
+    layout: &ChunkPathLayout,
+) -> anyhow::Result<Arc<dyn ChunkFetcher + Send + Sync>> {
+    let path = path.as_ref();
+    let extension = path.extension().map(|x| x.to_ascii_lowercase());
+    if tar::is_tarball(path) {
+        Ok(Arc::new(TarChunkFetcher::new(path, layout)?))
+    } else if extension == Some("zip".into()) {
+        Ok(Arc::new(ZipChunkFetcher::new(path, layout)?))
+    } else if path.is_dir() {
+        Ok(Arc::new(DirChunkFetcher::new(path, index_all)?))
+    } else {
+        Err(anyhow!("Unknown snapshot file type: {}", path.display()))
+    }
+}
+
 pub trait ReadSeek: Read + Seek {}
 
 impl<X: Read + Seek> ReadSeek for X {}
@@ -592,6 +844,13 @@ impl DirDiffFilesCollector {
         }
         Ok(Self { names: tree })
     }
+
+    /// Path of diff file `diff_name`
+    pub fn path(&self, diff_name: &str) -> Option<PathBuf> {
+        self.names
+            .get(diff_name)
+            .map(|root| root.join(format!("{diff_name}.diff")))
+    }
 }
 
 impl DiffFilesCollector for DirDiffFilesCollector {
@@ -663,6 +922,67 @@ impl DiffFilesCollector for SqfsDiffFilesCollector {
     }
 }
 
+/// Diff files given one by one, in the order they're applied. A diff is named by the snapshot
+/// name in its file name, or by the file name itself if there's none.
+pub struct DiffFileListCollector {
+    names: Vec<Iso8601Name>,
+    paths: HashMap<Iso8601Name, PathBuf>,
+}
+
+impl DiffFileListCollector {
+    pub fn new(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> anyhow::Result<Self> {
+        let mut names = Vec::new();
+        let mut map = HashMap::new();
+        for path in paths {
+            let path = path.as_ref();
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid diff path: {}", path.display()))?;
+            let name =
+                extract_datetime(file_name).unwrap_or_else(|| file_name.to_string_lossy().into());
+            if map.insert(name.clone(), path.to_path_buf()).is_some() {
+                yeet!(anyhow!("Diff {name} is given more than once"));
+            }
+            names.push(name);
+        }
+        if names.is_empty() {
+            yeet!(anyhow!("No diff files given"));
+        }
+        Ok(Self { names, paths: map })
+    }
+}
+
+impl DiffFilesCollector for DiffFileListCollector {
+    fn reader(&self, diff_name: &str) -> anyhow::Result<Box<dyn ReadSeek>> {
+        let path = self
+            .paths
+            .get(diff_name)
+            .ok_or_else(|| anyhow!("No entry: {diff_name}"))?;
+        Ok(Box::new(File::open_buffered(path)?))
+    }
+
+    fn contains(&self, diff_name: &str) -> bool {
+        self.paths.contains_key(diff_name)
+    }
+
+    fn name_iter<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = &'a Iso8601Name> + 'a> {
+        Box::new(self.names.iter())
+    }
+}
+
+/// Open diff sources; either all directories or all SquashFS images.
+pub fn open_diff_source(
+    paths: &[impl AsRef<Path>],
+) -> anyhow::Result<Arc<dyn DiffFilesCollector + Send + Sync>> {
+    if paths.iter().all(|x| x.as_ref().is_file()) {
+        Ok(Arc::new(SqfsDiffFilesCollector::new(paths)?))
+    } else if paths.iter().all(|x| x.as_ref().is_dir()) {
+        Ok(Arc::new(DirDiffFilesCollector::new(paths)?))
+    } else {
+        Err(anyhow!("SquashFS and Dir diff inputs cannot be mixed."))
+    }
+}
+
 pub macro chunk_buf() {
     vec![0_u8; CHUNK_LENGTH]
 }
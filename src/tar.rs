@@ -1,11 +1,44 @@
-use crate::{ChunkNumber, open_file_range};
+use crate::checksum::ChunkStates;
+use crate::layout::ChunkPathLayout;
+use crate::{ChunkNumber, parent_dir};
+use anyhow::anyhow;
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
 use lazy_regex::regex;
-use std::collections::BTreeMap;
+use log::{debug, warn};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
-use std::io;
-use std::io::{BufReader, Take};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use tar::EntryType;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use std::{fs, io};
+use tar::{EntryType, Header};
+use tempfile::NamedTempFile;
+use yeet_ops::yeet;
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+/// Magic of the tar index files
+pub const INDEX_MAGIC: [u8; 14] = *b"wplace-tar-idx";
+pub const INDEX_VERSION: u16 = 1;
+
+/// Magic of the chunk state files
+pub const STATES_MAGIC: [u8; 17] = *b"wplace-tar-states";
+pub const STATES_VERSION: u16 = 1;
+
+/// Magic at the end of the decompressed copies of tarballs
+pub const DECOMPRESSED_MAGIC: [u8; 23] = *b"wplace-tar-decompressed";
+/// Size of the trailer of a decompressed copy:
+/// `size: u64 | mtime_nanos: u128 | data_len: u64 | magic` (little endian)
+const DECOMPRESSED_TRAILER_LEN: u64 = 8 + 16 + 8 + DECOMPRESSED_MAGIC.len() as u64;
+
+/// Max decompressed size of a zstd frame in a compressed tarball.
+///
+/// Reading a chunk decompresses its whole frame, so tarballs with bigger frames (e.g. written
+/// by plain `zstd`) are read from a decompressed copy instead; see [`decompressed_path`].
+pub const MAX_ZSTD_FRAME_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Total decompressed size of the frames kept for reading further chunks from them
+const FRAME_CACHE_SIZE: u64 = 256 * 1024 * 1024;
 
 #[derive(Copy, Clone)]
 pub struct Range {
@@ -13,69 +46,1105 @@ pub struct Range {
     pub size: u64,
 }
 
+/// Start of a zstd frame in a compressed tarball
+#[derive(Copy, Clone, Debug)]
+pub struct ZstdFrame {
+    pub compressed_start: u64,
+    pub decompressed_start: u64,
+}
+
+/// Whether `path` names a snapshot tarball: `.tar`, or zstd-compressed `.tar.zst`, optionally
+/// split into parts of which this is the first one (`.tar.001`, `.tar.zst.001`).
+pub fn is_tarball(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| {
+            let stem = match split_part(x) {
+                Some((stem, number)) if number.parse::<u32>() == Ok(1) => stem,
+                Some(_) => return false,
+                None => x,
+            };
+            regex!(r"(?i)\.tar(\.zst)?$").is_match(stem)
+        })
+}
+
+/// Split a file name of a tarball part into the tarball name and the part number, e.g.
+/// `snapshot.tar.001` into `snapshot.tar` and `001`.
+fn split_part(name: &str) -> Option<(&str, &str)> {
+    regex!(r"^(.*)\.(\d{3,})$")
+        .captures(name)
+        .map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str()))
+}
+
+/// Files of the tarball `path`: all its parts if it's split, otherwise itself.
+pub fn tarball_files(path: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(TarStorage::open(path.as_ref())?
+        .parts
+        .into_iter()
+        .map(|x| x.0)
+        .collect())
+}
+
+/// Files a tarball is stored in. The tarball may be split into parts `<name>.001`,
+/// `<name>.002`, ..., and may be zstd-compressed as a whole.
+#[derive(Clone)]
+struct TarStorage {
+    /// Parts with their sizes, in order
+    parts: Vec<(PathBuf, u64)>,
+    zstd: bool,
+}
+
+impl TarStorage {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| anyhow!("Invalid tarball name: {}", path.display()))?;
+        let (name, paths) = match split_part(name) {
+            Some((stem, number)) => {
+                if number.parse::<u32>()? != 1 {
+                    yeet!(anyhow!(
+                        "Not the first part of a tarball: {}",
+                        path.display()
+                    ));
+                }
+                let mut paths = Vec::new();
+                for i in 1.. {
+                    let part =
+                        path.with_file_name(format!("{stem}.{i:0width$}", width = number.len()));
+                    if !part.is_file() {
+                        break;
+                    }
+                    paths.push(part);
+                }
+                (stem.to_string(), paths)
+            }
+            None => (name.into(), vec![path.into()]),
+        };
+        let parts = paths
+            .into_iter()
+            .map(|x| {
+                let size = fs::metadata(&x)?.len();
+                io::Result::Ok((x, size))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            parts,
+            zstd: name.to_ascii_lowercase().ends_with(".zst"),
+        })
+    }
+
+    /// Index key with the total size and the latest modification time of the parts
+    fn key(&self, layout: &ChunkPathLayout) -> io::Result<IndexKey> {
+        let mut key = IndexKey {
+            size: 0,
+            mtime_nanos: 0,
+            layout: layout.to_string(),
+        };
+        for (path, _) in &self.parts {
+            let metadata = fs::metadata(path)?;
+            key.size += metadata.len();
+            key.mtime_nanos = key.mtime_nanos.max(
+                metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(io::Error::other)?
+                    .as_nanos(),
+            );
+        }
+        Ok(key)
+    }
+
+    /// Decompress the tarball to `file`, followed by the trailer with `key` (see
+    /// [`DECOMPRESSED_TRAILER_LEN`]). Returns the decompressed size.
+    fn decompress_to(&self, file: &File, key: &IndexKey) -> io::Result<u64> {
+        let mut writer = BufWriter::new(file);
+        let mut decoder = zstd::Decoder::with_buffer(self.reader_at(0)?)?;
+        let size = io::copy(&mut decoder, &mut writer)?;
+        writer.write_u64::<LE>(key.size)?;
+        writer.write_u128::<LE>(key.mtime_nanos)?;
+        writer.write_u64::<LE>(size)?;
+        writer.write_all(&DECOMPRESSED_MAGIC)?;
+        writer.flush()?;
+        Ok(size)
+    }
+
+    /// File name of the tarball (of the first part if it's split)
+    fn name(&self) -> String {
+        self.parts[0]
+            .0
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Open the (compressed) content at `pos`.
+    fn reader_at(&self, pos: u64) -> io::Result<PartsReader<'_>> {
+        let mut offset = pos;
+        for (i, (path, size)) in self.parts.iter().enumerate() {
+            if offset < *size || i == self.parts.len() - 1 {
+                let mut file = File::open_buffered(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                return Ok(PartsReader {
+                    parts: &self.parts,
+                    current: i,
+                    file,
+                });
+            }
+            offset -= size;
+        }
+        Err(io::Error::other("Tarball has no parts"))
+    }
+}
+
+/// Reader of the concatenated parts
+struct PartsReader<'a> {
+    parts: &'a [(PathBuf, u64)],
+    current: usize,
+    file: BufReader<File>,
+}
+
+impl Read for PartsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for PartsReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.file.fill_buf()?.is_empty() && self.current + 1 < self.parts.len() {
+            self.current += 1;
+            self.file = File::open_buffered(&self.parts[self.current].0)?;
+        }
+        self.file.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.file.consume(amount);
+    }
+}
+
+/// Zstd decoder recording where each frame starts
+struct FrameRecorder<R: BufRead> {
+    reader: R,
+    decoder: zstd::stream::raw::Decoder<'static>,
+    compressed_pos: u64,
+    decompressed_pos: u64,
+    in_frame: bool,
+    frames: Vec<ZstdFrame>,
+}
+
+impl<R: BufRead> FrameRecorder<R> {
+    fn new(reader: R) -> io::Result<Self> {
+        Ok(Self {
+            reader,
+            decoder: zstd::stream::raw::Decoder::new()?,
+            compressed_pos: 0,
+            decompressed_pos: 0,
+            in_frame: false,
+            frames: Vec::new(),
+        })
+    }
+}
+
+impl<R: BufRead> Read for FrameRecorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let input = self.reader.fill_buf()?;
+            if input.is_empty() {
+                if self.in_frame {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Incomplete zstd frame",
+                    ));
+                }
+                return Ok(0);
+            }
+            if !self.in_frame {
+                self.in_frame = true;
+                self.frames.push(ZstdFrame {
+                    compressed_start: self.compressed_pos,
+                    decompressed_start: self.decompressed_pos,
+                });
+            }
+            let mut input = InBuffer::around(input);
+            let mut output = OutBuffer::around(&mut *buf);
+            // 0 means the frame is completely decoded and flushed
+            let hint = self.decoder.run(&mut input, &mut output)?;
+            let (read, written) = (input.pos(), output.pos());
+            self.reader.consume(read);
+            self.compressed_pos += read as u64;
+            self.decompressed_pos += written as u64;
+            if hint == 0 {
+                self.in_frame = false;
+                self.decoder.reinit()?;
+                // skippable frames
+                if self.frames.last().map(|x| x.decompressed_start) == Some(self.decompressed_pos) {
+                    self.frames.pop();
+                }
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
 pub struct ChunksTarReader {
     pub map: BTreeMap<ChunkNumber, Range>,
-    path: PathBuf,
+    storage: TarStorage,
+    /// Frames of a compressed tarball
+    frames: Vec<ZstdFrame>,
+    /// Recently decompressed frames by their index, the newest last
+    frame_cache: Mutex<VecDeque<(usize, Arc<Vec<u8>>)>>,
+    /// Decompressed content of a tarball with too big frames, which `storage` points to
+    _decompressed: Option<NamedTempFile>,
     pub root_name: String,
 }
 
+/// Size and modification time of a tarball, and the chunk path layout it's indexed by; an
+/// index file is valid only for the same key.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct IndexKey {
+    size: u64,
+    mtime_nanos: u128,
+    layout: String,
+}
+
+/// Path of the index file of `tar`: `<tar>.idx`, e.g. `snapshot.tar.idx`.
+pub fn index_path(tar: impl AsRef<Path>) -> PathBuf {
+    let mut path = tar.as_ref().as_os_str().to_owned();
+    path.push(".idx");
+    path.into()
+}
+
+/// Path of the chunk state file of `tar`: `<tar>.states`, e.g. `snapshot.tar.states`.
+pub fn states_path(tar: impl AsRef<Path>) -> PathBuf {
+    let mut path = tar.as_ref().as_os_str().to_owned();
+    path.push(".states");
+    path.into()
+}
+
+/// Path of the decompressed copy of `tar`, kept if it has too big zstd frames (see
+/// [`MAX_ZSTD_FRAME_SIZE`]): `<tar>.decompressed`, e.g. `snapshot.tar.zst.decompressed`.
+pub fn decompressed_path(tar: impl AsRef<Path>) -> PathBuf {
+    let mut path = tar.as_ref().as_os_str().to_owned();
+    path.push(".decompressed");
+    path.into()
+}
+
+/// Decompressed size of the content of the decompressed copy `path`, if it's made from the
+/// tarball with `key`.
+fn read_decompressed_trailer(path: &Path, key: &IndexKey) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < DECOMPRESSED_TRAILER_LEN {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(len - DECOMPRESSED_TRAILER_LEN))?;
+    let size = file.read_u64::<LE>()?;
+    let mtime_nanos = file.read_u128::<LE>()?;
+    let data_len = file.read_u64::<LE>()?;
+    let mut magic = [0_u8; DECOMPRESSED_MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    let valid = magic == DECOMPRESSED_MAGIC
+        && (size, mtime_nanos) == (key.size, key.mtime_nanos)
+        && data_len == len - DECOMPRESSED_TRAILER_LEN;
+    Ok(valid.then_some(data_len))
+}
+
+/// Content of an index file
+struct TarIndex {
+    map: BTreeMap<ChunkNumber, Range>,
+    root_name: String,
+    frames: Vec<ZstdFrame>,
+}
+
 impl ChunksTarReader {
-    pub fn open_with_index(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let (index, root_name) = Self::index_chunks(&path)?;
-        Ok(Self {
-            map: index,
-            path: path.as_ref().into(),
+    /// Open a tarball with chunk files in `layout`, with its chunk index. See [`is_tarball`]
+    /// for the supported forms.
+    ///
+    /// The index is read from the index file next to the tarball (see [`index_path`]) if it
+    /// matches the tarball's size and modification time. Otherwise the tarball is scanned,
+    /// and the index file is (re)written for later uses.
+    pub fn open_with_index(
+        path: impl AsRef<Path>,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<Self> {
+        Self::open(path, layout, true)
+    }
+
+    /// Scan the tarball for its chunk index, ignoring any index file.
+    pub fn open_without_cache(
+        path: impl AsRef<Path>,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<Self> {
+        Self::open(path, layout, false)
+    }
+
+    /// Open a tarball with chunk files in `layout`. With `cache`, the index file is used as in
+    /// [`Self::open_with_index`].
+    pub fn open(
+        path: impl AsRef<Path>,
+        layout: &ChunkPathLayout,
+        cache: bool,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let storage = TarStorage::open(path)?;
+        if !cache {
+            let index = Self::index_chunks(&storage, layout)?;
+            return Self::new(storage, index, false);
+        }
+
+        let key = storage.key(layout)?;
+        let index_file = index_path(path);
+        if index_file.exists() {
+            match read_index_file(&index_file, &key) {
+                Ok(Some(index)) => return Self::new(storage, index, true),
+                Ok(None) => debug!("Outdated tar index: {}", index_file.display()),
+                Err(e) => warn!("Failed to read tar index {}: {e}", index_file.display()),
+            }
+        }
+
+        let index = Self::index_chunks(&storage, layout)?;
+        if let Err(e) = write_index_file(&index_file, &key, &index) {
+            warn!("Failed to write tar index {}: {e}", index_file.display());
+        }
+        Self::new(storage, index, true)
+    }
+
+    /// Scan the tarball for chunk files in `layout` and write its index file. Returns the
+    /// number of indexed chunks.
+    pub fn build_index_file(
+        path: impl AsRef<Path>,
+        layout: &ChunkPathLayout,
+    ) -> anyhow::Result<usize> {
+        let path = path.as_ref();
+        let storage = TarStorage::open(path)?;
+        let key = storage.key(layout)?;
+        let index = Self::index_chunks(&storage, layout)?;
+        write_index_file(&index_path(path), &key, &index)?;
+        Ok(index.map.len())
+    }
+
+    /// Reader of the tarball `storage` with `index`. A tarball with too big zstd frames is read
+    /// from a decompressed copy: with `cache`, the one at [`decompressed_path`], reused while
+    /// the tarball is unchanged; otherwise a temporary one next to the tarball.
+    fn new(storage: TarStorage, index: TarIndex, cache: bool) -> anyhow::Result<Self> {
+        let mut reader = Self {
+            map: index.map,
+            storage,
+            frames: index.frames,
+            frame_cache: Mutex::new(VecDeque::new()),
+            _decompressed: None,
+            root_name: index.root_name,
+        };
+        // the last frame is taken to end with the last chunk
+        let data_end = reader.map.values().map(|x| x.start + x.size).max();
+        let ends = reader.frames.iter().skip(1).map(|x| x.decompressed_start);
+        let max_frame_size = reader
+            .frames
+            .iter()
+            .zip(ends.map(Some).chain([data_end]))
+            .map(|(frame, end)| {
+                end.unwrap_or_default()
+                    .saturating_sub(frame.decompressed_start)
+            })
+            .max()
+            .unwrap_or_default();
+        if max_frame_size > MAX_ZSTD_FRAME_SIZE {
+            let path = &reader.storage.parts[0].0;
+            let cached = decompressed_path(path);
+            // the decompressed content doesn't depend on the layout
+            let key = reader.storage.key(&ChunkPathLayout::default())?;
+            let cached_size = match cache && cached.exists() {
+                true => read_decompressed_trailer(&cached, &key).unwrap_or_else(|e| {
+                    warn!("Failed to read {}: {e}", cached.display());
+                    None
+                }),
+                false => None,
+            };
+            let (decompressed, size) = match cached_size {
+                Some(size) => (cached, size),
+                None => {
+                    warn!(
+                        "{} has a zstd frame of {max_frame_size} bytes, which is too big for \
+                         random access (max: {MAX_ZSTD_FRAME_SIZE}); decompressing it to {}. \
+                         Recompress it in smaller frames (e.g. with `pzstd` or `t2sz`) to \
+                         avoid this.",
+                        reader.storage.name(),
+                        match cache {
+                            true => cached.display().to_string(),
+                            false => "a temporary file".into(),
+                        }
+                    );
+                    let temp_file = NamedTempFile::new_in(parent_dir(path))?;
+                    let size = reader.storage.decompress_to(temp_file.as_file(), &key)?;
+                    if cache {
+                        temp_file.persist(&cached)?;
+                        (cached, size)
+                    } else {
+                        let decompressed = temp_file.path().into();
+                        reader._decompressed = Some(temp_file);
+                        (decompressed, size)
+                    }
+                }
+            };
+            reader.storage = TarStorage {
+                parts: vec![(decompressed, size)],
+                zstd: false,
+            };
+            reader.frames = Vec::new();
+        }
+        Ok(reader)
+    }
+
+    /// Decompressed content of frame `i`, from the cache if possible.
+    fn frame(&self, i: usize) -> io::Result<Arc<Vec<u8>>> {
+        let mut cache = self.frame_cache.lock().unwrap();
+        if let Some(pos) = cache.iter().position(|x| x.0 == i) {
+            let entry = cache.remove(pos).unwrap();
+            let data = Arc::clone(&entry.1);
+            cache.push_back(entry);
+            return Ok(data);
+        }
+        drop(cache);
+        let mut data = Vec::new();
+        zstd::Decoder::with_buffer(self.storage.reader_at(self.frames[i].compressed_start)?)?
+            .single_frame()
+            .read_to_end(&mut data)?;
+        let data = Arc::new(data);
+
+        let mut cache = self.frame_cache.lock().unwrap();
+        cache.push_back((i, Arc::clone(&data)));
+        while cache.len() > 1
+            && cache.iter().map(|x| x.1.len() as u64).sum::<u64>() > FRAME_CACHE_SIZE
+        {
+            cache.pop_front();
+        }
+        drop(cache);
+        Ok(data)
+    }
+
+    fn index_chunks(storage: &TarStorage, layout: &ChunkPathLayout) -> anyhow::Result<TarIndex> {
+        let reader = storage.reader_at(0)?;
+        let name = storage.name();
+        if !storage.zstd {
+            let (map, root_name) = Self::scan_entries(reader, layout, &name)?;
+            return Ok(TarIndex {
+                map,
+                root_name,
+                frames: Vec::new(),
+            });
+        }
+
+        let mut recorder = FrameRecorder::new(reader)?;
+        let (map, root_name) = Self::scan_entries(&mut recorder, layout, &name)?;
+        // read the remaining frames to the end
+        io::copy(&mut recorder, &mut io::sink())?;
+        Ok(TarIndex {
+            map,
             root_name,
+            frames: recorder.frames,
         })
     }
 
-    fn index_chunks(
-        path: impl AsRef<Path>,
+    /// Index chunk files by `layout`. `name` is the tarball name, used as the root name if the
+    /// layout has no root directory.
+    fn scan_entries(
+        reader: impl Read,
+        layout: &ChunkPathLayout,
+        name: &str,
     ) -> anyhow::Result<(BTreeMap<ChunkNumber, Range>, String)> {
         let mut map = BTreeMap::new();
+        let mut matcher = layout.matcher()?;
 
-        let mut tar = tar::Archive::new(File::open_buffered(path)?);
-
-        let mut entries = tar.entries()?;
-        let first = entries.next().expect("No entries")?;
-        // the first one is expected to be the root directory
-        assert_eq!(first.header().entry_type(), EntryType::Directory);
-        let root_name = first
-            .path()?
-            .file_name()
-            .expect("Can't get filename")
-            .to_str()
-            .expect("Invalid UTF-8")
-            .to_string();
-        for x in entries {
+        let mut tar = tar::Archive::new(reader);
+        for x in tar.entries()? {
             let x = x?;
-            if x.header().entry_type() != EntryType::Regular {
+            let path = x.path_bytes();
+            let Ok(path) = str::from_utf8(&path) else {
+                matcher.record_unexpected(&String::from_utf8_lossy(&path));
                 continue;
+            };
+            match x.header().entry_type() {
+                EntryType::Directory => continue,
+                EntryType::Regular => {}
+                _ => {
+                    matcher.record_unexpected(path);
+                    continue;
+                }
             }
-            let regex = regex!(r".*/(\d+)/(\d+)\.png$");
-            let cow = x.path()?;
-            let path_str = cow.to_str().expect("Invalid UTF-8");
-            assert!(regex.is_match(path_str));
-            let group = regex.captures(path_str).unwrap();
-            let chunk_number: ChunkNumber = (
-                group.get(1).unwrap().as_str().parse()?,
-                group.get(2).unwrap().as_str().parse()?,
-            );
+            let Some(chunk_number) = matcher.match_path(path) else {
+                continue;
+            };
             let range = Range {
                 start: x.raw_file_position(),
                 size: x.size(),
             };
-            map.insert(chunk_number, range);
+            if map.insert(chunk_number, range).is_some() {
+                yeet!(anyhow!(
+                    "Duplicate chunk {chunk_number:?} in {name}: {path}"
+                ));
+            }
         }
+        matcher.finish(name, map.len())?;
+        // without a root directory, name the snapshot after the tarball
+        let root_name = matcher.root().map_or_else(
+            || {
+                let stem = split_part(name).map_or(name, |x| x.0);
+                regex!(r"(?i)\.tar(\.zst)?$").replace(stem, "").into_owned()
+            },
+            String::from,
+        );
         Ok((map, root_name))
     }
 
-    pub fn open_chunk(
-        &self,
-        chunk_number: ChunkNumber,
-    ) -> Option<io::Result<Take<BufReader<File>>>> {
+    /// Read the PNG file of a chunk. Returns None if the chunk is absent.
+    pub fn read_chunk(&self, chunk_number: ChunkNumber) -> Option<io::Result<Vec<u8>>> {
         self.map
             .get(&chunk_number)
-            .map(|range| open_file_range(&self.path, range.start, range.size))
+            .map(|&range| self.read_range(range))
+    }
+
+    fn read_range(&self, range: Range) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(range.size as usize);
+        if self.storage.zstd {
+            // the range may span several frames
+            let end = range.start + range.size;
+            let mut pos = range.start;
+            while pos < end {
+                let i = self
+                    .frames
+                    .partition_point(|x| x.decompressed_start <= pos)
+                    .saturating_sub(1);
+                let frame = self.frame(i)?;
+                let start = self.frames[i].decompressed_start;
+                let from = (pos - start) as usize;
+                let to = ((end - start) as usize).min(frame.len());
+                if from >= to {
+                    break;
+                }
+                buf.extend_from_slice(&frame[from..to]);
+                pos = start + to as u64;
+            }
+        } else {
+            self.storage
+                .reader_at(range.start)?
+                .take(range.size)
+                .read_to_end(&mut buf)?;
+        }
+        if buf.len() as u64 != range.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Truncated tarball",
+            ));
+        }
+        Ok(buf)
+    }
+}
+
+/// Read an index file. Returns None if it's written for another `key` or by another version.
+///
+/// Layout (after zstd decompression; little endian):
+/// `magic | version: u16 | size: u64 | mtime_nanos: u128 | layout_len: u16 | layout |
+/// root_name_len: u16 | root_name |
+/// count: u64 | count * (x: u16, y: u16, start: u64, size: u64) |
+/// frame_count: u64 | frame_count * (compressed_start: u64, decompressed_start: u64)`
+fn read_index_file(path: &Path, key: &IndexKey) -> anyhow::Result<Option<TarIndex>> {
+    let mut reader = zstd::Decoder::new(File::open(path)?)?;
+    let mut magic = [0_u8; INDEX_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != INDEX_MAGIC {
+        yeet!(anyhow!("Not a tar index file"));
+    }
+    if reader.read_u16::<LE>()? != INDEX_VERSION || &read_key(&mut reader)? != key {
+        return Ok(None);
+    }
+    let root_name = read_string(&mut reader)?;
+    let count = reader.read_u64::<LE>()?;
+    let mut map = BTreeMap::new();
+    for _ in 0..count {
+        let n = (reader.read_u16::<LE>()?, reader.read_u16::<LE>()?);
+        let range = Range {
+            start: reader.read_u64::<LE>()?,
+            size: reader.read_u64::<LE>()?,
+        };
+        map.insert(n, range);
+    }
+    let frame_count = reader.read_u64::<LE>()?;
+    let mut frames = Vec::new();
+    for _ in 0..frame_count {
+        frames.push(ZstdFrame {
+            compressed_start: reader.read_u64::<LE>()?,
+            decompressed_start: reader.read_u64::<LE>()?,
+        });
+    }
+    Ok(Some(TarIndex {
+        map,
+        root_name,
+        frames,
+    }))
+}
+
+fn read_key(reader: &mut impl Read) -> anyhow::Result<IndexKey> {
+    Ok(IndexKey {
+        size: reader.read_u64::<LE>()?,
+        mtime_nanos: reader.read_u128::<LE>()?,
+        layout: read_string(reader)?,
+    })
+}
+
+fn write_key(writer: &mut impl Write, key: &IndexKey) -> anyhow::Result<()> {
+    writer.write_u64::<LE>(key.size)?;
+    writer.write_u128::<LE>(key.mtime_nanos)?;
+    write_string(writer, &key.layout)
+}
+
+fn read_string(reader: &mut impl Read) -> anyhow::Result<String> {
+    let mut buf = vec![0_u8; reader.read_u16::<LE>()? as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+fn write_string(writer: &mut impl Write, s: &str) -> anyhow::Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| anyhow!("String too long: {s}"))?;
+    writer.write_u16::<LE>(len)?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_index_file(path: &Path, key: &IndexKey, index: &TarIndex) -> anyhow::Result<()> {
+    let temp_file = NamedTempFile::new_in(parent_dir(path))?;
+    let mut writer = zstd::Encoder::new(File::create_buffered(temp_file.as_ref())?, 3)?;
+    writer.write_all(&INDEX_MAGIC)?;
+    writer.write_u16::<LE>(INDEX_VERSION)?;
+    write_key(&mut writer, key)?;
+    write_string(&mut writer, &index.root_name)?;
+    writer.write_u64::<LE>(index.map.len() as u64)?;
+    for (&(x, y), range) in &index.map {
+        writer.write_u16::<LE>(x)?;
+        writer.write_u16::<LE>(y)?;
+        writer.write_u64::<LE>(range.start)?;
+        writer.write_u64::<LE>(range.size)?;
+    }
+    writer.write_u64::<LE>(index.frames.len() as u64)?;
+    for frame in &index.frames {
+        writer.write_u64::<LE>(frame.compressed_start)?;
+        writer.write_u64::<LE>(frame.decompressed_start)?;
+    }
+    writer.finish()?.flush()?;
+    temp_file.persist(path)?;
+    Ok(())
+}
+
+/// Read the chunk states of the tarball `path` indexed by `layout` from its state file (see
+/// [`states_path`]). Returns None if there's none, or if it's written for another version of
+/// the tarball.
+///
+/// Layout (after zstd decompression; little endian):
+/// `magic | version: u16 | size: u64 | mtime_nanos: u128 | layout_len: u16 | layout |
+/// count: u64 | count * (x: u16, y: u16, checksum: u32, state_digest: u64)`
+pub fn read_states_file(
+    path: impl AsRef<Path>,
+    layout: &ChunkPathLayout,
+) -> anyhow::Result<Option<ChunkStates>> {
+    let states_file = states_path(&path);
+    if !states_file.exists() {
+        return Ok(None);
+    }
+    let key = TarStorage::open(path.as_ref())?.key(layout)?;
+    let mut reader = zstd::Decoder::new(File::open(&states_file)?)?;
+    let mut magic = [0_u8; STATES_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != STATES_MAGIC {
+        yeet!(anyhow!("Not a chunk state file"));
+    }
+    if reader.read_u16::<LE>()? != STATES_VERSION || read_key(&mut reader)? != key {
+        debug!("Outdated chunk states: {}", states_file.display());
+        return Ok(None);
+    }
+    let count = reader.read_u64::<LE>()?;
+    let mut states = ChunkStates::new();
+    for _ in 0..count {
+        let n = (reader.read_u16::<LE>()?, reader.read_u16::<LE>()?);
+        states.insert(n, (reader.read_u32::<LE>()?, reader.read_u64::<LE>()?));
+    }
+    Ok(Some(states))
+}
+
+/// Write `states` of chunks of the tarball `path` indexed by `layout` to its state file. The
+/// states of other chunks are kept if the file is still valid.
+pub fn write_states_file(
+    path: impl AsRef<Path>,
+    layout: &ChunkPathLayout,
+    states: &ChunkStates,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let key = TarStorage::open(path)?.key(layout)?;
+    let mut all = read_states_file(path, layout)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    all.extend(states);
+    let mut sorted = all.into_iter().collect::<Vec<_>>();
+    sorted.sort_unstable_by_key(|x| x.0);
+
+    let states_file = states_path(path);
+    let temp_file = NamedTempFile::new_in(parent_dir(&states_file))?;
+    let mut writer = zstd::Encoder::new(File::create_buffered(temp_file.as_ref())?, 3)?;
+    writer.write_all(&STATES_MAGIC)?;
+    writer.write_u16::<LE>(STATES_VERSION)?;
+    write_key(&mut writer, &key)?;
+    writer.write_u64::<LE>(sorted.len() as u64)?;
+    for ((x, y), (checksum, digest)) in sorted {
+        writer.write_u16::<LE>(x)?;
+        writer.write_u16::<LE>(y)?;
+        writer.write_u32::<LE>(checksum)?;
+        writer.write_u64::<LE>(digest)?;
+    }
+    writer.finish()?.flush()?;
+    temp_file.persist(states_file)?;
+    Ok(())
+}
+
+/// Writer of snapshot tarballs in the wplace-archives layout: `<root>/<x>/<y>.png`, with the
+/// root directory as the first entry.
+///
+/// Chunks have to be added in ascending (x, y) order. Entry metadata is fixed, so identical
+/// chunks produce identical tarballs.
+pub struct ChunksTarWriter<W: Write> {
+    builder: tar::Builder<W>,
+    root_name: String,
+    last: Option<ChunkNumber>,
+}
+
+impl<W: Write> ChunksTarWriter<W> {
+    pub fn new(writer: W, root_name: &str) -> io::Result<Self> {
+        let mut builder = tar::Builder::new(writer);
+        builder.append_data(
+            &mut entry_header(EntryType::Directory, 0),
+            format!("{root_name}/"),
+            io::empty(),
+        )?;
+        Ok(Self {
+            builder,
+            root_name: root_name.into(),
+            last: None,
+        })
+    }
+
+    pub fn add_chunk(&mut self, (x, y): ChunkNumber, png: &[u8]) -> io::Result<()> {
+        if let Some(last) = self.last
+            && last >= (x, y)
+        {
+            return Err(io::Error::other(format!(
+                "Chunks must be added in ascending order: {:?} after {last:?}",
+                (x, y)
+            )));
+        }
+        if self.last.map(|x| x.0) != Some(x) {
+            self.builder.append_data(
+                &mut entry_header(EntryType::Directory, 0),
+                format!("{}/{x}/", self.root_name),
+                io::empty(),
+            )?;
+        }
+        self.builder.append_data(
+            &mut entry_header(EntryType::Regular, png.len() as u64),
+            format!("{}/{x}/{y}.png", self.root_name),
+            png,
+        )?;
+        self.last = Some((x, y));
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        self.builder.into_inner()
+    }
+}
+
+fn entry_header(entry_type: EntryType, size: u64) -> Header {
+    let mut header = Header::new_ustar();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(match entry_type {
+        EntryType::Directory => 0o755,
+        _ => 0o644,
+    });
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const ROOT: &str = "2025-08-09T20-01-14.231Z";
+
+    /// Chunk contents of the test tarball; big enough to span parts and frames
+    fn chunks() -> Vec<(ChunkNumber, Vec<u8>)> {
+        (0..6_u16)
+            .map(|i| {
+                let n = (600 + i / 3, i % 3);
+                let data = (0..1500 + 300 * i as usize).map(|x| (x * 7 + i as usize) as u8);
+                (n, data.collect())
+            })
+            .collect()
+    }
+
+    fn tar_bytes() -> Vec<u8> {
+        let mut writer = ChunksTarWriter::new(Vec::new(), ROOT).unwrap();
+        for (n, data) in chunks() {
+            writer.add_chunk(n, &data).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    /// Compress `data` as a zstd frame every `frame_size` bytes
+    fn zstd_frames(data: &[u8], frame_size: usize) -> Vec<u8> {
+        data.chunks(frame_size)
+            .flat_map(|x| zstd::encode_all(x, 3).unwrap())
+            .collect()
+    }
+
+    /// Write `data` split into parts of `part_size` bytes. Returns the path of the first part.
+    fn write_parts(dir: &Path, name: &str, data: &[u8], part_size: usize) -> PathBuf {
+        for (i, part) in data.chunks(part_size).enumerate() {
+            fs::write(dir.join(format!("{name}.{:03}", i + 1)), part).unwrap();
+        }
+        dir.join(format!("{name}.001"))
+    }
+
+    fn assert_chunks(reader: &ChunksTarReader) {
+        assert_eq!(reader.root_name, ROOT);
+        assert_eq!(reader.map.len(), chunks().len());
+        for (n, data) in chunks() {
+            assert_eq!(reader.read_chunk(n).unwrap().unwrap(), data, "chunk {n:?}");
+        }
+        assert!(reader.read_chunk((0, 0)).is_none());
+    }
+
+    #[test]
+    fn tarball_names() {
+        for name in ["a.tar", "a.TAR", "a.tar.zst", "a.tar.001", "a.tar.zst.0001"] {
+            assert!(is_tarball(name), "{name}");
+        }
+        for name in ["a.zip", "a.tar.002", "a.tar.1", "a.zst", "a.tar.zst.000"] {
+            assert!(!is_tarball(name), "{name}");
+        }
+        assert_eq!(split_part("a.tar.zst.001"), Some(("a.tar.zst", "001")));
+        assert_eq!(split_part("a.tar"), None);
+    }
+
+    #[test]
+    fn parts_reader() {
+        let dir = TempDir::new().unwrap();
+        let data = (0..10_000).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let first = write_parts(dir.path(), "a.tar", &data, 3000);
+        let storage = TarStorage::open(&first).unwrap();
+        assert_eq!(storage.parts.len(), 4);
+        assert!(!storage.zstd);
+        for pos in [0, 2999, 3000, 6500, 9999, 10_000] {
+            let mut buf = Vec::new();
+            storage
+                .reader_at(pos)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            assert_eq!(buf, data[pos as usize..], "at {pos}");
+        }
+        assert!(TarStorage::open(&dir.path().join("a.tar.002")).is_err());
+    }
+
+    #[test]
+    fn frame_recorder() {
+        let data = (0..10_000).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let compressed = zstd_frames(&data, 4000);
+        let mut recorder = FrameRecorder::new(&compressed[..]).unwrap();
+        let mut decompressed = Vec::new();
+        recorder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+
+        let starts = recorder
+            .frames
+            .iter()
+            .map(|x| x.decompressed_start)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0, 4000, 8000]);
+        for frame in &recorder.frames {
+            let mut buf = Vec::new();
+            zstd::Decoder::new(&compressed[frame.compressed_start as usize..])
+                .unwrap()
+                .single_frame()
+                .read_to_end(&mut buf)
+                .unwrap();
+            let start = frame.decompressed_start as usize;
+            assert_eq!(buf, data[start..(start + buf.len())]);
+        }
+    }
+
+    #[test]
+    fn frame_recorder_truncated() {
+        let compressed = zstd_frames(&[1_u8; 1000], 1000);
+        let mut recorder = FrameRecorder::new(&compressed[..compressed.len() - 1]).unwrap();
+        assert!(recorder.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn read_plain() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.tar");
+        fs::write(&path, tar_bytes()).unwrap();
+        let reader = ChunksTarReader::open_without_cache(&path, &Default::default()).unwrap();
+        assert!(reader.frames.is_empty());
+        assert_chunks(&reader);
+    }
+
+    #[test]
+    fn read_split() {
+        let dir = TempDir::new().unwrap();
+        let first = write_parts(dir.path(), "a.tar", &tar_bytes(), 1000);
+        let reader = ChunksTarReader::open_without_cache(&first, &Default::default()).unwrap();
+        assert_chunks(&reader);
+        assert_eq!(
+            tarball_files(&first).unwrap().len(),
+            reader.storage.parts.len()
+        );
+    }
+
+    #[test]
+    fn read_multi_frame_zst() {
+        let dir = TempDir::new().unwrap();
+        // frames smaller than chunks, so chunks span several of them
+        let compressed = zstd_frames(&tar_bytes(), 700);
+        let first = write_parts(dir.path(), "a.tar.zst", &compressed, 900);
+        let reader = ChunksTarReader::open_without_cache(&first, &Default::default()).unwrap();
+        assert!(reader.frames.len() > 1);
+        assert!(reader._decompressed.is_none());
+        assert_chunks(&reader);
+        // again from the frame cache
+        assert_chunks(&reader);
+    }
+
+    #[test]
+    fn read_big_frame_zst() {
+        let dir = TempDir::new().unwrap();
+        let layout = ChunkPathLayout::default();
+        let big = (602, 0);
+        let big_data = vec![7_u8; MAX_ZSTD_FRAME_SIZE as usize + 1];
+        let mut writer = ChunksTarWriter::new(Vec::new(), ROOT).unwrap();
+        for (n, data) in chunks() {
+            writer.add_chunk(n, &data).unwrap();
+        }
+        writer.add_chunk(big, &big_data).unwrap();
+        let path = dir.path().join("a.tar.zst");
+        // a single frame
+        fs::write(
+            &path,
+            zstd::encode_all(&writer.finish().unwrap()[..], 1).unwrap(),
+        )
+        .unwrap();
+        let cached = decompressed_path(&path);
+        let check = |reader: &ChunksTarReader| {
+            assert!(!reader.storage.zstd);
+            for (n, data) in chunks() {
+                assert_eq!(reader.read_chunk(n).unwrap().unwrap(), data, "chunk {n:?}");
+            }
+            assert!(reader.read_chunk(big).unwrap().unwrap() == big_data);
+        };
+
+        // without the cache, a temporary copy next to the tarball
+        let reader = ChunksTarReader::open_without_cache(&path, &layout).unwrap();
+        check(&reader);
+        let temp = reader._decompressed.as_ref().unwrap().path().to_owned();
+        assert_eq!(temp.parent(), Some(dir.path()));
+        drop(reader);
+        assert!(!temp.exists());
+        assert!(!cached.exists());
+
+        // with it, a kept copy reused while the tarball is unchanged
+        let modified = || fs::metadata(&cached).unwrap().modified().unwrap();
+        let reader = ChunksTarReader::open_with_index(&path, &layout).unwrap();
+        check(&reader);
+        assert!(reader._decompressed.is_none());
+        assert_eq!(reader.storage.parts[0].0, cached);
+        let first = modified();
+        let reader = ChunksTarReader::open_with_index(&path, &layout).unwrap();
+        check(&reader);
+        assert_eq!(modified(), first);
+
+        let later = fs::metadata(&path).unwrap().modified().unwrap() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let reader = ChunksTarReader::open_with_index(&path, &layout).unwrap();
+        check(&reader);
+        assert_ne!(modified(), first);
+    }
+
+    #[test]
+    fn index_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.tar.zst");
+        fs::write(&path, zstd_frames(&tar_bytes(), 2000)).unwrap();
+        let layout = ChunkPathLayout::default();
+        assert_eq!(
+            ChunksTarReader::build_index_file(&path, &layout).unwrap(),
+            chunks().len()
+        );
+        let key = TarStorage::open(&path).unwrap().key(&layout).unwrap();
+        let index = read_index_file(&index_path(&path), &key).unwrap().unwrap();
+        assert_eq!(index.root_name, ROOT);
+        assert!(index.frames.len() > 1);
+
+        let reader = ChunksTarReader::open_with_index(&path, &layout).unwrap();
+        assert_chunks(&reader);
+        // another layout doesn't match the index
+        let other = ChunkPathLayout {
+            extension: "webp".into(),
+            ..Default::default()
+        };
+        let key = TarStorage::open(&path).unwrap().key(&other).unwrap();
+        assert!(read_index_file(&index_path(&path), &key).unwrap().is_none());
+    }
+
+    #[test]
+    fn states_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.tar");
+        fs::write(&path, tar_bytes()).unwrap();
+        let layout = ChunkPathLayout::default();
+        assert!(read_states_file(&path, &layout).unwrap().is_none());
+
+        // states of further chunks are merged into the file
+        write_states_file(&path, &layout, &ChunkStates::from([((600, 0), (1, 2))])).unwrap();
+        write_states_file(&path, &layout, &ChunkStates::from([((601, 2), (3, 4))])).unwrap();
+        assert_eq!(
+            read_states_file(&path, &layout).unwrap().unwrap(),
+            ChunkStates::from([((600, 0), (1, 2)), ((601, 2), (3, 4))])
+        );
+
+        // a rewritten tarball doesn't match them
+        let mut bytes = tar_bytes();
+        bytes.extend([0; 512]);
+        fs::write(&path, bytes).unwrap();
+        assert!(read_states_file(&path, &layout).unwrap().is_none());
     }
 }
@@ -2,17 +2,18 @@
 //! Utility to read files inside a ZIP directly.
 
 use crate::indexed_png::read_png_reader;
+use crate::layout::ChunkPathLayout;
 use crate::{CHUNK_LENGTH, ChunkNumber};
-use lazy_regex::regex;
-use rawzip::RECOMMENDED_BUFFER_SIZE;
-use rawzip::path::{RawPath, ZipFilePath};
+use anyhow::anyhow;
+use rawzip::{CompressionMethod, RECOMMENDED_BUFFER_SIZE};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use yeet_ops::yeet;
 
-type ChunkIndexMap = HashMap<ChunkNumber, (u64, u64)>;
+pub type ChunkIndexMap = HashMap<ChunkNumber, (u64, u64)>;
 
 pub struct ChunksZipReader {
     zip_file: BufReader<File>,
@@ -20,8 +21,8 @@ pub struct ChunksZipReader {
 }
 
 impl ChunksZipReader {
-    pub fn open(zip: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let map = collect_zip_entries(&zip)?;
+    pub fn open(zip: impl AsRef<Path>, layout: &ChunkPathLayout) -> anyhow::Result<Self> {
+        let map = collect_zip_entries(&zip, layout)?;
         Ok(Self {
             map,
             zip_file: File::open_buffered(zip)?,
@@ -36,60 +37,108 @@ impl ChunksZipReader {
         self.zip_file.seek(SeekFrom::Start(range.0))?;
         let take = self.zip_file.by_ref().take(range.1);
         let mut buf = vec![0_u8; CHUNK_LENGTH];
-        read_png_reader(take, &mut buf).unwrap();
+        read_png_reader(take, &mut buf).map_err(io::Error::other)?;
         Ok(Some(buf))
     }
 }
 
-fn collect_zip_entries(path: impl AsRef<Path>) -> anyhow::Result<ChunkIndexMap> {
+/// Index chunk files of a ZIP laid out as `layout`: (position, length) of the data of each
+/// chunk.
+///
+/// Only stored (uncompressed) entries can be read directly. Entries not matching the layout
+/// are skipped with a warning.
+pub fn collect_zip_entries(
+    path: impl AsRef<Path>,
+    layout: &ChunkPathLayout,
+) -> anyhow::Result<ChunkIndexMap> {
+    let path = path.as_ref();
+    let mut matcher = layout.matcher()?;
     let mut buffer = [0_u8; RECOMMENDED_BUFFER_SIZE];
     let zip = rawzip::ZipArchive::from_file(File::open(path)?, &mut buffer)?;
     let mut entries = zip.entries(&mut buffer);
-    assert!(zip.entries_hint() > 1);
-    let first = entries.next_entry()?.unwrap();
-    // expect the first one is the root directory
-    let file_path = unwrap_file_path(first.file_path());
-    assert!(file_path.ends_with('/'));
-    let root_path = String::from(file_path);
 
     let mut map = HashMap::new();
-    loop {
-        let Some(e) = entries.next_entry()? else {
-            break;
+    while let Some(e) = entries.next_entry()? {
+        let Ok(file_path) = std::str::from_utf8(e.file_path().as_bytes()) else {
+            matcher.record_unexpected(&String::from_utf8_lossy(e.file_path().as_bytes()));
+            continue;
         };
-        let file_path = unwrap_file_path(e.file_path());
-        assert!(file_path.starts_with(&root_path));
-        let filename = &file_path[root_path.len()..];
-
-        // skip directory entries
-        if filename.ends_with('/') {
+        if e.is_dir() {
             continue;
         }
+        let Some(n) = matcher.match_path(file_path) else {
+            continue;
+        };
 
-        let chunk_path_regex = regex!(r"^(\d+)/(\d+)\.png$");
-        assert!(chunk_path_regex.is_match(filename));
-        let captures = chunk_path_regex.captures(filename).unwrap();
-        let chunk_x = captures
-            .get(1)
-            .unwrap()
-            .as_str()
-            .parse::<u16>()
-            .expect("Not an integer");
-        let chunk_y = captures
-            .get(2)
-            .unwrap()
-            .as_str()
-            .parse::<u16>()
-            .expect("Not an integer");
-
-        let data_range = zip.get_entry(e.wayfinder())?.compressed_data_range();
-        map.insert((chunk_x, chunk_y), data_range);
+        if e.compression_method() != CompressionMethod::Store {
+            yeet!(anyhow!(
+                "Compressed ZIP entries are not supported: {file_path}"
+            ));
+        }
+        let (start, end) = zip.get_entry(e.wayfinder())?.compressed_data_range();
+        if map.insert(n, (start, end - start)).is_some() {
+            yeet!(anyhow!("Duplicate chunk {n:?} in {}", path.display()));
+        }
     }
+    matcher.finish(&path.display().to_string(), map.len())?;
 
     Ok(map)
 }
 
-#[inline(always)]
-fn unwrap_file_path(path: ZipFilePath<RawPath<'_>>) -> &str {
-    std::str::from_utf8(path.as_bytes()).expect("Invalid UTF-8")
+/// Writer of snapshot ZIPs in the same layout as [`crate::tar::ChunksTarWriter`]:
+/// `<root>/<x>/<y>.png`, with the root directory as the first entry.
+///
+/// Entries are stored uncompressed so they can be read directly, and carry no timestamps.
+/// Chunks have to be added in ascending (x, y) order.
+pub struct ChunksZipWriter<W: Write> {
+    archive: rawzip::ZipArchiveWriter<W>,
+    root_name: String,
+    last: Option<ChunkNumber>,
+}
+
+impl<W: Write> ChunksZipWriter<W> {
+    pub fn new(writer: W, root_name: &str) -> anyhow::Result<Self> {
+        let mut archive = rawzip::ZipArchiveWriter::new(writer);
+        archive
+            .new_dir(&format!("{root_name}/"))
+            .unix_permissions(0o755)
+            .create()?;
+        Ok(Self {
+            archive,
+            root_name: root_name.into(),
+            last: None,
+        })
+    }
+
+    pub fn add_chunk(&mut self, (x, y): ChunkNumber, png: &[u8]) -> anyhow::Result<()> {
+        if let Some(last) = self.last
+            && last >= (x, y)
+        {
+            yeet!(anyhow!(
+                "Chunks must be added in ascending order: {:?} after {last:?}",
+                (x, y)
+            ));
+        }
+        if self.last.map(|x| x.0) != Some(x) {
+            self.archive
+                .new_dir(&format!("{}/{x}/", self.root_name))
+                .unix_permissions(0o755)
+                .create()?;
+        }
+        let (mut entry, config) = self
+            .archive
+            .new_file(&format!("{}/{x}/{y}.png", self.root_name))
+            .unix_permissions(0o644)
+            .start()?;
+        let mut writer = config.wrap(&mut entry);
+        writer.write_all(png)?;
+        let (_, output) = writer.finish()?;
+        entry.finish(output)?;
+        self.last = Some((x, y));
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<W> {
+        Ok(self.archive.finish()?)
+    }
 }